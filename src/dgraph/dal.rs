@@ -1,200 +1,876 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
-use super::DgraphClient;
-use super::RDFable;
-use crate::music::library::Library;
-use crate::music::track::Track;
-use crate::CanariaError;
+use super::{DgraphClient, DgraphError};
+use super::{dql_string, set_object};
+use crate::music::library::{FileStamp, Library};
+use crate::music::metadata::name_key;
+use crate::music::picture::Picture;
+use crate::music::track::{ArtistCredit, Track};
+
+/// Number of audio files fetched per query when listing library files
+const FILES_PAGE_SIZE: usize = 1000;
+
+/// Audio file predicates making up a `FileStamp`
+const FILE_STAMP_FIELDS: &str = "uid\n\
+    file_path: AudioObject.filepath\n\
+    file_size: AudioObject.sizeKilobytes\n\
+    file_modified: AudioObject.modified\n\
+    content_hash: AudioObject.contentHash\n\
+    missing: AudioObject.missing\n\
+    cover_modified: AudioObject.coverModified\n\
+    cue_modified: AudioObject.cueModified\n\
+    lyrics_modified: AudioObject.lyricsModified\n";
 
 impl DgraphClient {
     // MARK: Business related methods
 
     /// Provides Library struct up2date with persistent data
-    pub async fn get_library(&self, name: String, path: PathBuf) -> Result<Library, CanariaError> {
-        let upsert_lib = format!(
-            "upsert {{\
-                query {{\
-                    q(func: eq(<Library.name>, \"{}\")){{\
-                        lib as uid\
-                    }}\
-                }}\
-                mutation {{\
-                    set {{\n\
-                        uid(lib) <dgraph.type> \"Library\".\n\
-                        uid(lib) <Library.name> \"{0}\" .\n\
-                        uid(lib) <Library.path> \"{}\" .\n\
-                    }}\
-                }}\
-            }}",
-            name,
-            path.display(),
-        );
+    pub async fn get_library(&self, name: String, path: PathBuf) -> Result<Library, DgraphError> {
+        let upsert_lib = json!({
+            "query": format!(
+                "{{\n\
+                    q(func: eq(<Library.name>, {})) {{\n\
+                        lib as uid\n\
+                    }}\n\
+                }}",
+                dql_string(&name),
+            ),
+            "set": {
+                "uid": "uid(lib)",
+                "dgraph.type": "Library",
+                "Library.name": name,
+                "Library.path": path.to_string_lossy(),
+            },
+        });
 
-        self.mutate(upsert_lib.as_str()).await?;
+        self.mutate(&upsert_lib).await?;
 
         let result = self.query_single::<Library>(
-            format!(
-                "{{\
-                    var(func: eq(<Library.name>, \"{}\")) {{\n\
-                        <Library.track> {{\n\
-                            d as MusicRecording.durationSeconds\n\
-                            s as MusicRecording.sizeKilobytes\n\
-                        }}\n\
-                        sumS as sum(val(s))\n\
-                        sumD as sum(val(d))\n\
-                    }}\n\
-                    q(func: eq(<Library.name>, \"{0}\")) {{\n\
-                        name : Library.name\n\
-                        path: Library.path\n\
-                        duration_seconds: val(sumD)\n\
-                        size_kilobytes: val(sumS)\n\
-                    }}\
-                }}",
-                name
-            ).as_str()).await?;
-            
+            "query lib($name: string) {\n\
+                var(func: eq(<Library.name>, $name)) {\n\
+                    <Library.track> {\n\
+                        d as MusicRecording.durationSeconds\n\
+                        s as MusicRecording.sizeKilobytes\n\
+                    }\n\
+                    sumS as sum(val(s))\n\
+                    sumD as sum(val(d))\n\
+                }\n\
+                q(func: eq(<Library.name>, $name)) {\n\
+                    name : Library.name\n\
+                    path: Library.path\n\
+                    duration_seconds: val(sumD)\n\
+                    size_kilobytes: val(sumS)\n\
+                }\n\
+            }",
+            &HashMap::from([("$name", name)]),
+        ).await?;
+
         match result {
             Some(lib) => Ok(lib),
-            None => Err("library update/insertion failed".into())
+            None => Err(DgraphError::MissingResult("library".into()))
         }
     }
 
-    /// Provides Track struct up2date with persistent data
-    pub async fn update_track(
+    /// Provides ingestion stamps of every audio file linked to a library,
+    /// indexed by file path
+    pub async fn get_library_files(
         &self,
-        track: Track,
-        lib: &mut Library,
-    ) -> Result<Track, CanariaError> {
-        let dql = format!(
-            "\
-            upsert {{\
-                query {{\
-                    l as var(func: eq(Library.name, \"{lib}\"))\n\
-                    {ar_vars}\
-                    al as var(func: eq(Album.mbid, \"{al_ref}\"))\n\
-                    au as var(func: eq(AudioObject.filepath, \"{au_ref}\"))
-                    t as var(func: eq(MusicRecording.mbid, \"{t_ref}\"))\n\
-                }}\n\
-                mutation {{\
-                    set {{\n\
-                        uid(l) <Library.track> uid(t) .\n\
-                        {ar_muts_nqd}\
-                        uid(al) <dgraph.type> \"MusicAlbum\" .\n\
-                        uid(al) <MusicAlbum.track> uid(t) .\n\
-                        {al_title_nqd}\
-                        uid(t) <dgraph.type> \"MusicRecording\" .\n\
-                        uid(t) <MusicRecording.mbid> \"{t_ref}\" .\n\
-                        uid(t) <CreativeWork.title> \"{t_title}\" .\n\
-                        uid(t) <CreativeWork.artist> \"{t_ar}\" .\n\
-                        uid(t) <MusicRecording.inAlbum> uid(al) .\n\
-                        uid(t) <MusicRecording.audio> uid(au) .\n\
-                        {t_dur_nqd}\
-                        {t_year_nqd}\
-                        uid(au) <dgraph.type> \"AudioObject\" . \n\
-                        uid(au) <AudioObject.sizeKilobytes> \"{t_size}\" .\n\
-                        uid(au) <AudioObject.filepath> \"{au_ref}\" .\n\
-                        uid(au) <AudioObject.extension> \"{au_ext}\" .\n\
-                        uid(au) <AudioObject.encodingFormat> \"{au_enc}\" .\n\
-                        {au_pic_nqd}\
-                        {au_picmime_nqd}\
-                    }}\
-                }}\
-            }}",
-            lib = lib.name,
-            ar_vars = track.artists_vars(),
-            ar_muts_nqd = track.artists_muts("uid(l)", "uid(t)"),
-            al_ref = track.album_ref.unwrap_or("".into()),
-            al_title_nqd = track.album.nqd("uid(al)", "<CreativeWork.title>"),
-            au_ref = track.file_path.to_string_lossy(),
-            au_ext = track.extension,
-            au_enc = track.mime_type,
-            au_pic_nqd = track.picture.nqd("uid(au)", "<AudioObject.picture>"),
-            au_picmime_nqd = track.picture_mime_type.nqd("uid(au)", "<AudioObject.pictureMimeType>"),
-            t_ref = track.track_ref,
-            t_title = track.title,
-            t_ar = track.artist,
-            t_year_nqd = track
-                .original_year
-                .nqd("uid(t)", "<CreativeWork.originalYear>"),
-            t_dur_nqd = track
-                .duration_seconds
-                .nqd("uid(t)", "<MusicRecording.durationSeconds>"),
-            t_size = track.file_size,
-        );
-        self.mutate(dql.as_str()).await?;
-
-        let result = self
-            .query_single(format!(
-                "{{\
-                    q(func: eq(<MusicRecording.mbid>, \"{}\")) @normalize {{\n\
-                        title: CreativeWork.title\n\
-                        artist: CreativeWork.artist\n\
-                        artist_ref: Artist.mbid\n\
-                        original_year: CreativeWork.originalYear\n\
-                        track_ref: MusicRecording.mbid\n\
-                        <MusicRecording.audio> {{\n\
-                            extension: AudioObject.extension\n\
-                            file_path: AudioObject.filepath\n\
-                            file_size: AudioObject.sizeKilobytes\n\
-                            mime_type: AudioObject.encodingFormat\n\
-                            picture: AudioObject.picture\n\
-                            picture_mime_type: AudioObject.pictureMimeType\n\
-                        }}\
-                    }}\
+        lib: &Library,
+    ) -> Result<HashMap<PathBuf, FileStamp>, DgraphError> {
+        let mut out = HashMap::new();
+        let mut after: String = "0x0".into();
+        loop {
+            let page: Vec<FileStamp> = self
+                .query_all(
+                    format!(
+                        "query files($name: string) {{\n\
+                            var(func: eq(<Library.name>, $name)) {{\n\
+                                <Library.track> {{\n\
+                                    au as MusicRecording.audio\n\
+                                }}\n\
+                                untagged as <Library.audio>\n\
+                            }}\n\
+                            q(func: uid(au, untagged), first: {}, after: {}) {{\n\
+                                {}\
+                            }}\n\
+                        }}",
+                        FILES_PAGE_SIZE, after, FILE_STAMP_FIELDS
+                    )
+                    .as_str(),
+                    &HashMap::from([("$name", lib.name.clone())]),
+                )
+                .await?;
+            let page_len = page.len();
+            if let Some(last) = page.last() {
+                after = last.uid.clone();
+            }
+            for stamp in page {
+                out.insert(stamp.file_path.clone(), stamp);
+            }
+            if page_len < FILES_PAGE_SIZE {
+                break;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Provides ingestion stamps of audio files found at `paths` (files
+    /// unknown to database are left out), indexed by file path
+    pub async fn get_files(&self, paths: &[PathBuf]) -> Result<HashMap<PathBuf, FileStamp>, DgraphError> {
+        let mut out = HashMap::new();
+        for chunk in paths.chunks(FILES_PAGE_SIZE) {
+            let paths: Vec<String> = chunk
+                .iter()
+                .map(|path| dql_string(&path.to_string_lossy()))
+                .collect();
+            let stamps: Vec<FileStamp> = self
+                .query_all(
+                    format!(
+                        "{{\n\
+                            q(func: eq(AudioObject.filepath, [{}])) {{\n\
+                                {}\
+                            }}\n\
+                        }}",
+                        paths.join(", "),
+                        FILE_STAMP_FIELDS
+                    )
+                    .as_str(),
+                    &HashMap::new(),
+                )
+                .await?;
+            for stamp in stamps {
+                out.insert(stamp.file_path.clone(), stamp);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Removes an audio file node along with the recordings left without any
+    /// audio file
+    pub async fn delete_audio_object(&self, uid: &str, lib: &Library) -> Result<(), DgraphError> {
+        let upsert = json!({
+            "query": format!(
+                "{{\n\
+                    l as var(func: eq(Library.name, {lib}))\n\
+                    au as var(func: uid({uid})) {{\n\
+                        t as ~MusicRecording.audio\n\
+                    }}\n\
+                    orphan as var(func: uid(t)) @filter(eq(count(MusicRecording.audio), 1)) {{\n\
+                        al as MusicRecording.inAlbum\n\
+                    }}\n\
+                    var(func: uid(al)) {{\n\
+                        MusicAlbum.track @filter(not uid(orphan)) {{\n\
+                            kept as MusicRecording.inAlbum\n\
+                        }}\n\
+                    }}\n\
+                    emptied as var(func: uid(al)) @filter(not uid(kept))\n\
                 }}",
-                track.track_ref,
-            ).as_str())
-            .await?;
+                lib = dql_string(&lib.name),
+                uid = uid,
+            ),
+            "delete": [
+                { "uid": "uid(t)", "MusicRecording.audio": { "uid": "uid(au)" } },
+                { "uid": "uid(au)" },
+                { "uid": "uid(l)", "Library.audio": { "uid": "uid(au)" } },
+                { "uid": "uid(l)", "Library.track": { "uid": "uid(orphan)" } },
+                { "uid": "uid(al)", "MusicAlbum.track": { "uid": "uid(orphan)" } },
+                { "uid": "uid(orphan)" },
+                { "uid": "uid(emptied)" },
+            ],
+        });
+        self.mutate(&upsert).await
+    }
 
-        match result {
-            Some(track) => Ok(track),
-            None => Err("track insertion/update failed".into())
+    /// Flags an audio file node as missing from filesystem
+    pub async fn mark_audio_object_missing(&self, uid: &str) -> Result<(), DgraphError> {
+        self.mutate(&json!({ "set": { "uid": uid, "AudioObject.missing": true } }))
+            .await
+    }
+
+    /// Sends many tracks to database within a single upsert block
+    pub async fn update_tracks(&self, tracks: &[Track], lib: &Library) -> Result<(), DgraphError> {
+        self.mutate(&tracks_upsert(tracks, lib)).await
+    }
+}
+
+/// Upsert block adding many tracks to a library
+fn tracks_upsert(tracks: &[Track], lib: &Library) -> Value {
+    let mut batch = UpsertBatch::default();
+    let lib_var = batch.var("l", format!("eq(Library.name, {})", dql_string(&lib.name)));
+    for track in tracks {
+        track.add_recordings_to(&mut batch, &lib_var);
+    }
+    batch.drop_emptied_albums();
+    batch.to_json()
+}
+
+/// Upsert block gathering many tracks. Tracks referencing the same node
+/// (e.g. album or artist) share its query variable, so the node is not
+/// created twice within the batch
+#[derive(Default)]
+struct UpsertBatch {
+    /// Query variables declarations
+    vars: String,
+    /// Unconditional mutation objects
+    objects: Vec<Value>,
+    /// Unconditional deletion objects, applied before any mutation object
+    deletes: Vec<Value>,
+    /// Conditional mutations
+    mutations: Vec<Value>,
+    /// Conditional mutation index by condition
+    conds: HashMap<String, usize>,
+    /// Variable name by node selection
+    names: HashMap<String, String>,
+    /// Variables of the recordings leaving their album
+    moved: Vec<String>,
+    /// Variables of the albums left by recordings
+    left_albums: Vec<String>,
+    /// Variables of the albums joined by recordings
+    joined_albums: Vec<String>,
+}
+
+impl UpsertBatch {
+    /// Provides `uid()` reference of nodes selected by `func`, declaring its
+    /// variable on first use
+    fn var(&mut self, prefix: &str, func: String) -> String {
+        format!("uid({})", self.declare(prefix, func, ""))
+    }
+
+    /// Provides variable name of nodes selected by `func` followed by
+    /// `directives` (e.g. filters or a nested block, where `{var}` stands for
+    /// the variable name), declaring it on first use
+    fn declare(&mut self, prefix: &str, func: String, directives: &str) -> String {
+        let selection = format!("{}{}", func, directives);
+        if let Some(name) = self.names.get(&selection) {
+            return name.clone();
+        }
+        let name = format!("{}{}", prefix, self.names.len());
+        self.vars = format!(
+            "{}{} as var(func: {}){}\n",
+            self.vars,
+            name,
+            func,
+            directives.replace("{var}", &name)
+        );
+        self.names.insert(selection, name.clone());
+        name
+    }
+
+    /// Whether a variable of nodes selected by `func` (without directives)
+    /// is already declared
+    fn is_declared(&self, func: &str) -> bool {
+        self.names.contains_key(func)
+    }
+
+    /// Records `recording` leaving `left` albums, possibly for `joined` one
+    /// (`uid()` references), so albums it empties get dropped
+    fn move_recording(&mut self, recording: &str, left: &str, joined: Option<&str>) {
+        for (vars, reference) in [
+            (&mut self.moved, Some(recording)),
+            (&mut self.left_albums, Some(left)),
+            (&mut self.joined_albums, joined),
+        ] {
+            let name = reference.map(|reference| reference.trim_start_matches("uid(").trim_end_matches(')'));
+            if let Some(name) = name.filter(|name| !vars.iter().any(|var| var == name)) {
+                vars.push(name.to_string());
+            }
+        }
+    }
+
+    /// Deletes the albums left by recordings without any other track, as
+    /// album keys change along with tags or folder names
+    fn drop_emptied_albums(&mut self) {
+        if self.left_albums.is_empty() {
+            return;
+        }
+        let left = format!("uid({})", self.left_albums.join(", "));
+        // albums still holding a recording not moved
+        let kept = self.declare(
+            "ka",
+            left.clone(),
+            &format!(
+                " {{ MusicAlbum.track @filter(not uid({})) {{ {{var}}al as MusicRecording.inAlbum }} }}",
+                self.moved.join(", ")
+            ),
+        );
+        let mut filter = format!("not uid({}al)", kept);
+        if !self.joined_albums.is_empty() {
+            filter = format!("{} and not uid({})", filter, self.joined_albums.join(", "));
         }
+        let emptied = self.declare("ea", left, &format!(" @filter({})", filter));
+        self.delete(json!({ "uid": format!("uid({})", emptied) }));
+    }
+
+    /// Adds a mutation object, ignoring its null valued predicates
+    fn set(&mut self, object: Value) {
+        self.objects.push(set_object(object));
+    }
+
+    /// Adds a deletion object (where null predicates stand for all their
+    /// values), applied before mutation objects so predicates can be reset
+    fn delete(&mut self, object: Value) {
+        if !self.deletes.contains(&object) {
+            self.deletes.push(object);
+        }
+    }
+
+    /// Adds objects to the mutation applied only when `cond` holds, each
+    /// condition making a single mutation whatever the number of tracks
+    /// relying on it
+    fn mutation(&mut self, cond: String, set: Vec<Value>, delete: Vec<Value>) {
+        let index = match self.conds.get(&cond) {
+            Some(&index) => index,
+            None => {
+                self.mutations.push(json!({ "cond": cond, "set": [], "delete": [] }));
+                self.conds.insert(cond, self.mutations.len() - 1);
+                self.mutations.len() - 1
+            }
+        };
+        let mutation = &mut self.mutations[index];
+        for (kind, objects) in [("set", set.into_iter().map(set_object).collect()), ("delete", delete)] {
+            let known = mutation[kind].as_array_mut().expect("bogus mutation");
+            for object in objects {
+                if !known.contains(&object) {
+                    known.push(object);
+                }
+            }
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        // Dgraph applies the deletions of a mutation before its additions
+        let mut mutations = vec![json!({ "set": self.objects })];
+        if !self.deletes.is_empty() {
+            mutations[0]["delete"] = Value::from(self.deletes.clone());
+        }
+        for mutation in &self.mutations {
+            let mut mutation = mutation.clone();
+            if mutation["delete"].as_array().is_some_and(|delete| delete.is_empty()) {
+                mutation.as_object_mut().expect("bogus mutation").remove("delete");
+            }
+            mutations.push(mutation);
+        }
+        json!({
+            "query": format!("{{\n{}}}", self.vars),
+            "mutations": mutations,
+        })
     }
 }
 
 impl Track {
-    /// Return filter clauses to select referenced artists
-    fn artists_vars(&self) -> String {
-        let mut filter: String = "".into();
-        for (index, artist) in self.artist_ref.iter().enumerate() {
-            filter = format!("ar{} as var(func: eq(<Artist.mbid>, \"{}\"))\n", index, artist);
-        }
-        filter
-    }
-    
-    fn artists_muts(&self, lib_subject: &str, track_subject: &str) -> String {
-        let mut artists_names: Vec<String> = Vec::new();
-        if self.artist_ref.len() > 1 {
-            // TODO: find each artist name
-            log::debug!("track artist (string): {}", self.artist);
-            log::debug!("track reference (vec): {:?}", self.artist_ref);
-            log::error!("multi artists track not supported. repeating artist string");
-            for _ in self.artist_ref.iter() {
-                artists_names.insert(artists_names.len(), self.artist.clone());
-            }
+    /// Declares the recordings held by the file into an upsert batch, along
+    /// with the unlinking of those it no longer holds (e.g. the whole file
+    /// recording of a file since split by a CUE sheet, or any recording of a
+    /// file now needing tagging)
+    fn add_recordings_to(&self, batch: &mut UpsertBatch, lib: &str) {
+        let recordings = self.recordings();
+        let refs: Vec<String> = recordings
+            .iter()
+            .filter(|recording| !recording.needs_tagging)
+            .map(|recording| dql_string(&recording.track_ref))
+            .collect();
+        let held = if refs.is_empty() {
+            String::new()
         } else {
-            artists_names.insert(0, self.artist.clone())
-        }
-
-        // grant artist_ref.len() == artists_names.len()
-        let mut out = String::from("");
-        for (index, artist) in self.artist_ref.iter().enumerate() {
-            out = format!("{}\
-                    {lib} <Library.artist> uid(ar{index}) .\n\
-                    uid(ar{index}) <dgraph.type> \"Artist\" .\n\
-                    uid(ar{index}) <Artist.mbid> \"{reference}\" .\n\
-                    uid(ar{index}) <Artist.names> \"{name}\" .\n\
-                    {track} <CreativeWork.byArtist> uid(ar{index}) .\n\
-                ",
-                out,
-                lib = lib_subject,
-                track = track_subject,
-                index = index,
-                reference = artist,
-                name = artists_names[index]
-            )
-        }
-        out
-    }
-}
\ No newline at end of file
+            format!(" @filter(not eq(MusicRecording.mbid, [{}]))", refs.join(", "))
+        };
+        let au = batch.var(
+            "au",
+            format!("eq(AudioObject.filepath, {})", dql_string(&self.file_path.to_string_lossy())),
+        );
+        let stale = batch.declare(
+            "au",
+            format!("eq(AudioObject.filepath, {})", dql_string(&self.file_path.to_string_lossy())),
+            &format!(" {{ {{var}}t as ~MusicRecording.audio{} }}", held),
+        );
+        let orphan = batch.declare(
+            "o",
+            format!("uid({}t)", stale),
+            " @filter(eq(count(MusicRecording.audio), 1)) { {var}al as MusicRecording.inAlbum }",
+        );
+        let (stale, orphan_albums, orphan) = (
+            format!("uid({}t)", stale),
+            format!("uid({}al)", orphan),
+            format!("uid({})", orphan),
+        );
+        // recordings left without any audio file are dropped
+        batch.delete(json!({ "uid": stale, "MusicRecording.audio": { "uid": au } }));
+        batch.delete(json!({ "uid": lib, "Library.track": { "uid": orphan } }));
+        batch.delete(json!({ "uid": orphan_albums, "MusicAlbum.track": { "uid": orphan } }));
+        batch.delete(json!({ "uid": orphan }));
+        batch.move_recording(&orphan, &orphan_albums, None);
+
+        for recording in &recordings {
+            recording.add_to(batch, lib);
+        }
+    }
+
+    /// Declares track related nodes into an upsert batch
+    fn add_to(&self, batch: &mut UpsertBatch, lib: &str) {
+        let au = batch.var(
+            "au",
+            format!("eq(AudioObject.filepath, {})", dql_string(&self.file_path.to_string_lossy())),
+        );
+
+        let artwork = self.artwork().map(|picture| picture.add_to(batch));
+        let mut images: Vec<Value> = Vec::new();
+        for picture in &self.pictures {
+            let mut image = json!({ "uid": picture.add_to(batch) });
+            if let Some(usage) = picture.usage {
+                image["AudioObject.image|usage"] = Value::from(format!("{:?}", usage));
+            }
+            images.push(image);
+        }
+
+        batch.delete(json!({
+            "uid": au,
+            "AudioObject.image": null,
+            "AudioObject.artwork": null,
+            "AudioObject.qualityIssues": null,
+        }));
+        batch.set(json!({
+            "uid": au,
+            "dgraph.type": "AudioObject",
+            "AudioObject.sizeKilobytes": self.file_size,
+            "AudioObject.filepath": self.file_path.to_string_lossy(),
+            "AudioObject.extension": self.extension,
+            "AudioObject.encodingFormat": self.mime_type,
+            "AudioObject.modified": self.file_modified,
+            "AudioObject.coverModified": self.cover_modified,
+            "AudioObject.cueModified": self.cue_modified,
+            "AudioObject.lyricsModified": self.lyrics_modified,
+            "AudioObject.missing": false,
+            "AudioObject.contentHash": self.content_hash,
+            "AudioObject.codec": self.codec,
+            "AudioObject.sampleRate": self.sample_rate,
+            "AudioObject.bitsPerSample": self.bits_per_sample,
+            "AudioObject.channels": self.channels,
+            "AudioObject.channelLayout": self.channel_layout,
+            "AudioObject.bitrate": self.bitrate,
+            "AudioObject.image": Some(images).filter(|images| !images.is_empty()),
+            "AudioObject.artwork": artwork.as_ref().map(|img| json!({ "uid": img })),
+            "AudioObject.durationSeconds": self.duration_seconds,
+            "AudioObject.needsTagging": self.needs_tagging,
+            "AudioObject.qualityIssues": Some(&self.quality_issues).filter(|issues| !issues.is_empty()),
+        }));
+        if self.needs_tagging {
+            // only the audio file is known for sure, so no work gets recorded
+            batch.set(json!({ "uid": lib, "Library.audio": { "uid": au } }));
+            return;
+        }
+        // now reachable through its recordings
+        batch.delete(json!({ "uid": lib, "Library.audio": { "uid": au } }));
+
+        let al = match (&self.album_ref, self.album_key()) {
+            (Some(reference), _) => {
+                Some(batch.var("al", format!("eq(MusicAlbum.mbid, {})", dql_string(reference))))
+            }
+            (None, Some(key)) => Some(batch.var("al", format!("eq(MusicAlbum.key, {})", dql_string(&key)))),
+            (None, None) => None,
+        };
+        let t = batch.declare(
+            "t",
+            format!("eq(MusicRecording.mbid, {})", dql_string(&self.track_ref)),
+            " { {var}al as MusicRecording.inAlbum }",
+        );
+        let (t, t_albums) = (format!("uid({})", t), format!("uid({}al)", t));
+
+        // edges are reset, so those no longer tagged get unlinked
+        batch.delete(json!({
+            "uid": t,
+            "MusicRecording.genre": null,
+            "MusicRecording.tag": null,
+            "MusicRecording.inAlbum": null,
+            "MusicRecording.inferredFields": null,
+            "CreativeWork.byArtist": null,
+        }));
+        batch.delete(json!({ "uid": t_albums, "MusicAlbum.track": { "uid": t } }));
+        batch.move_recording(&t, &t_albums, al.as_deref());
+
+        let mut audio = json!({ "uid": au });
+        if let Some((start_ms, end_ms)) = self.span {
+            audio["MusicRecording.audio|startMs"] = Value::from(start_ms);
+            audio["MusicRecording.audio|endMs"] = Value::from(end_ms);
+        }
+
+        batch.set(json!({ "uid": lib, "Library.track": { "uid": t } }));
+        artists_muts(batch, lib, &t, self.artist_credits());
+        for genre in &self.genres {
+            let g = batch.var("g", format!("eq(Genre.key, {})", dql_string(&name_key(genre))));
+            batch.set(json!({
+                "uid": g,
+                "dgraph.type": "Genre",
+                "Genre.name": genre,
+                "Genre.key": name_key(genre),
+            }));
+            batch.set(json!({ "uid": t, "MusicRecording.genre": { "uid": g } }));
+        }
+        for tag in &self.tags {
+            let tg = batch.var("tg", format!("eq(Tag.key, {})", dql_string(&name_key(tag))));
+            batch.set(json!({
+                "uid": tg,
+                "dgraph.type": "Tag",
+                "Tag.name": tag,
+                "Tag.key": name_key(tag),
+            }));
+            batch.set(json!({ "uid": t, "MusicRecording.tag": { "uid": tg } }));
+        }
+        if let Some(al) = &al {
+            artists_muts(batch, lib, al, self.album_artist_credits());
+            batch.set(json!({
+                "uid": al,
+                "dgraph.type": "MusicAlbum",
+                "MusicAlbum.mbid": self.album_ref,
+                "MusicAlbum.key": self.album_key(),
+                "MusicAlbum.track": { "uid": t },
+                "CreativeWork.title": self.album,
+                "CreativeWork.artist": self.album_artist(),
+                "MusicAlbum.compilation": self.compilation,
+                "MusicAlbum.catalogNumber": self.catalog_number,
+                "MusicAlbum.image": artwork.as_ref().map(|img| json!({ "uid": img })),
+                "CreativeWork.originalYear": self.original_year,
+            }));
+        }
+        batch.set(json!({
+            "uid": t,
+            "dgraph.type": "MusicRecording",
+            "MusicRecording.mbid": self.track_ref,
+            "CreativeWork.title": self.title,
+            "CreativeWork.artist": self.artist,
+            "MusicRecording.inAlbum": al.map(|al| json!({ "uid": al })),
+            "MusicRecording.audio": audio,
+            "MusicRecording.durationSeconds": self.duration_seconds,
+            "MusicRecording.trackNumber": self.track_number,
+            "MusicRecording.trackTotal": self.track_total,
+            "MusicRecording.discNumber": self.disc_number,
+            "MusicRecording.discTotal": self.disc_total,
+            "CreativeWork.originalYear": self.original_year,
+            "MusicRecording.releaseDate": self.release_date,
+            "MusicRecording.originalReleaseDate": self.original_date,
+            "MusicRecording.lyrics": self.lyrics,
+            "MusicRecording.syncedLyrics": self.synced_lyrics,
+            "MusicRecording.inferredFields": Some(&self.inferred).filter(|inferred| !inferred.is_empty()),
+        }));
+    }
+}
+
+impl Picture {
+    /// Declares an image node into an upsert batch, providing its reference
+    fn add_to(&self, batch: &mut UpsertBatch) -> String {
+        let func = format!("eq(ImageObject.contentHash, {})", dql_string(&self.hash));
+        if batch.is_declared(&func) {
+            return batch.var("img", func);
+        }
+        let img = batch.var("img", func);
+        batch.set(json!({
+            "uid": img,
+            "dgraph.type": "ImageObject",
+            "ImageObject.contentHash": self.hash,
+            "ImageObject.encodingFormat": self.mime_type,
+            "ImageObject.width": self.dimensions.map(|(width, _)| width),
+            "ImageObject.height": self.dimensions.map(|(_, height)| height),
+            "ImageObject.sizeBytes": self.size,
+            "ImageObject.colorMode": self.color_mode,
+            "ImageObject.bitsPerPixel": self.bits_per_pixel,
+        }));
+        img
+    }
+}
+
+/// Declares artists credited by a work (e.g. recording or album):
+/// catalogued ones are matched by MusicBrainz ID, adopting (or merging into
+/// it) any uncatalogued node of the same name, while uncatalogued ones are
+/// matched by normalized name
+fn artists_muts(batch: &mut UpsertBatch, lib_subject: &str, work_subject: &str, credits: Vec<ArtistCredit>) {
+    for credit in credits {
+        let key = credit.name.as_deref().map(name_key);
+        let links = |ar: &str| {
+            vec![
+                json!({
+                    "uid": ar,
+                    "dgraph.type": "Artist",
+                    "Artist.mbid": credit.reference,
+                    "Artist.names": credit.name,
+                    "Artist.sortName": credit.sort_name,
+                    "Artist.key": key,
+                }),
+                json!({ "uid": lib_subject, "Library.artist": { "uid": ar } }),
+                json!({ "uid": work_subject, "CreativeWork.byArtist": { "uid": ar } }),
+            ]
+        };
+        match (&credit.reference, &key) {
+            (Some(reference), Some(key)) => {
+                let ar = batch.declare("ar", format!("eq(<Artist.mbid>, {})", dql_string(reference)), "");
+                let arl = batch.declare(
+                    "arl",
+                    format!("eq(<Artist.key>, {}), first: 1", dql_string(key)),
+                    " @filter(not has(<Artist.mbid>)) { {var}w as ~CreativeWork.byArtist {var}l as ~Library.artist }",
+                );
+                let (ar_uid, arl_uid, arlw_uid, arll_uid) = (
+                    format!("uid({})", ar),
+                    format!("uid({})", arl),
+                    format!("uid({}w)", arl),
+                    format!("uid({}l)", arl),
+                );
+                batch.mutation(
+                    format!("@if(gt(len({}), 0) OR eq(len({}), 0))", ar, arl),
+                    links(&ar_uid),
+                    vec![],
+                );
+                batch.mutation(
+                    format!("@if(eq(len({}), 0) AND gt(len({}), 0))", ar, arl),
+                    links(&arl_uid),
+                    vec![],
+                );
+                batch.mutation(
+                    format!("@if(gt(len({}), 0) AND gt(len({}), 0))", ar, arl),
+                    vec![
+                        json!({ "uid": arlw_uid, "CreativeWork.byArtist": { "uid": ar_uid } }),
+                        json!({ "uid": arll_uid, "Library.artist": { "uid": ar_uid } }),
+                    ],
+                    vec![
+                        json!({ "uid": arlw_uid, "CreativeWork.byArtist": { "uid": arl_uid } }),
+                        json!({ "uid": arll_uid, "Library.artist": { "uid": arl_uid } }),
+                        json!({ "uid": arl_uid }),
+                    ],
+                );
+            }
+            (Some(reference), None) => {
+                let ar = batch.var("ar", format!("eq(<Artist.mbid>, {})", dql_string(reference)));
+                for object in links(&ar) {
+                    batch.set(object);
+                }
+            }
+            (None, Some(key)) => {
+                let ar = batch.var("ar", format!("eq(<Artist.key>, {}), first: 1", dql_string(key)));
+                for object in links(&ar) {
+                    batch.set(object);
+                }
+            }
+            (None, None) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music::track::tests::tagged_track;
+    use regex::Regex;
+
+    fn credit(reference: &str, name: &str) -> ArtistCredit {
+        ArtistCredit {
+            reference: Some(reference.into()),
+            name: Some(name.into()),
+            sort_name: None,
+        }
+    }
+
+    #[test]
+    fn artist_merge_mutations_are_emitted_once() {
+        let mut batch = UpsertBatch::default();
+        let lib = batch.var("l", "eq(Library.name, \"lib\")".into());
+        for work in ["uid(t1)", "uid(t2)", "uid(t3)"] {
+            artists_muts(&mut batch, &lib, work, vec![credit("mbid-a", "A")]);
+        }
+        let upsert = batch.to_json();
+        let mutations = upsert["mutations"].as_array().unwrap();
+        // unconditional one, then adoption, catalogued and merge cases
+        assert_eq!(mutations.len(), 4);
+        // the artist node is set once, along with each work link
+        assert_eq!(mutations[1]["set"].as_array().unwrap().len(), 2 + 3);
+    }
+
+    #[test]
+    fn artist_merge_moves_every_library_link() {
+        let mut batch = UpsertBatch::default();
+        artists_muts(&mut batch, "uid(l0)", "uid(t1)", vec![credit("mbid-a", "A")]);
+        let upsert = batch.to_json();
+        assert!(upsert["query"].as_str().unwrap().contains("arl1l as ~Library.artist"));
+        let merge = &upsert["mutations"][3];
+        assert!(merge["set"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "uid": "uid(arl1l)", "Library.artist": { "uid": "uid(ar0)" } })));
+        assert!(merge["delete"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "uid": "uid(arl1l)", "Library.artist": { "uid": "uid(arl1)" } })));
+    }
+
+    #[test]
+    fn track_edges_are_reset_before_being_set() {
+        let mut batch = UpsertBatch::default();
+        tagged_track("mbid-t").add_to(&mut batch, "uid(l0)");
+        let upsert = batch.to_json();
+        let deletes = upsert["mutations"][0]["delete"].as_array().unwrap();
+        let t = upsert["mutations"][0]["set"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|object| object["dgraph.type"] == "MusicRecording")
+            .unwrap()["uid"]
+            .clone();
+        assert!(deletes
+            .iter()
+            .any(|object| object["uid"] == t && object["MusicRecording.genre"].is_null()
+                && object.get("MusicRecording.inferredFields").is_some()
+                && object.get("CreativeWork.byArtist").is_some()));
+        assert!(deletes
+            .iter()
+            .any(|object| object["MusicAlbum.track"]["uid"] == t));
+        assert!(deletes
+            .iter()
+            .any(|object| object.get("AudioObject.artwork").is_some()));
+    }
+
+
+    #[test]
+    fn recordings_no_longer_held_are_unlinked() {
+        let mut batch = UpsertBatch::default();
+        tagged_track("mbid-t").add_recordings_to(&mut batch, "uid(l0)");
+        let upsert = batch.to_json();
+        let query = upsert["query"].as_str().unwrap();
+        assert!(query.contains(
+            "au1 as var(func: eq(AudioObject.filepath, \"/music/a.flac\")) \
+             { au1t as ~MusicRecording.audio @filter(not eq(MusicRecording.mbid, [\"mbid-t\"])) }"
+        ));
+        assert!(query.contains("o2 as var(func: uid(au1t)) @filter(eq(count(MusicRecording.audio), 1))"));
+        let deletes = upsert["mutations"][0]["delete"].as_array().unwrap();
+        assert!(deletes.contains(&json!({ "uid": "uid(au1t)", "MusicRecording.audio": { "uid": "uid(au0)" } })));
+        assert!(deletes.contains(&json!({ "uid": "uid(l0)", "Library.track": { "uid": "uid(o2)" } })));
+        assert!(deletes.contains(&json!({ "uid": "uid(o2al)", "MusicAlbum.track": { "uid": "uid(o2)" } })));
+        assert!(deletes.contains(&json!({ "uid": "uid(o2)" })));
+    }
+
+
+    #[test]
+    fn recordings_of_files_needing_tagging_are_unlinked() {
+        let mut untagged = tagged_track("mbid-t");
+        untagged.needs_tagging = true;
+        let mut batch = UpsertBatch::default();
+        untagged.add_recordings_to(&mut batch, "uid(l0)");
+        let upsert = batch.to_json();
+        assert!(upsert["query"]
+            .as_str()
+            .unwrap()
+            .contains("{ au1t as ~MusicRecording.audio }"));
+        let mutation = &upsert["mutations"][0];
+        assert!(mutation["delete"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "uid": "uid(au1t)", "MusicRecording.audio": { "uid": "uid(au0)" } })));
+        assert!(mutation["set"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "uid": "uid(l0)", "Library.audio": { "uid": "uid(au0)" } })));
+        assert!(!upsert["query"].as_str().unwrap().contains("MusicRecording.mbid"));
+    }
+
+    #[test]
+    fn tagged_files_leave_untagged_library_audio() {
+        let mut batch = UpsertBatch::default();
+        tagged_track("mbid-t").add_to(&mut batch, "uid(l0)");
+        let upsert = batch.to_json();
+        assert!(upsert["mutations"][0]["delete"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "uid": "uid(l0)", "Library.audio": { "uid": "uid(au0)" } })));
+    }
+
+
+    #[test]
+    fn special_characters_are_kept_in_upserts() {
+        let lib = Library {
+            name: "The \"Best\" of\\".into(),
+            path: "/music".into(),
+            duration_seconds: 0,
+            size_kilobytes: 0,
+        };
+        let mut special = tagged_track("mbid-t");
+        special.title = "Ends with a backslash \\".into();
+        special.artist = "Line\nbreak".into();
+        special.file_path = "/music/\"Quoted\"\\.flac".into();
+        let upsert = tracks_upsert(&[special], &lib);
+
+        // string literals of the upsert query decode to the original values
+        let query = upsert["query"].as_str().unwrap();
+        let literal = |func: &str| -> String {
+            let start = query.find(func).unwrap() + func.len();
+            let end = query[start..].find(')').unwrap();
+            serde_json::from_str(&query[start..start + end]).unwrap()
+        };
+        assert_eq!(literal("eq(Library.name, "), lib.name);
+        assert_eq!(literal("eq(AudioObject.filepath, "), "/music/\"Quoted\"\\.flac");
+
+        let set = upsert["mutations"][0]["set"].as_array().unwrap();
+        let recording = set.iter().find(|object| object["dgraph.type"] == "MusicRecording").unwrap();
+        assert_eq!(recording["CreativeWork.title"], "Ends with a backslash \\");
+        assert_eq!(recording["CreativeWork.artist"], "Line\nbreak");
+        let audio = set.iter().find(|object| object["dgraph.type"] == "AudioObject").unwrap();
+        assert_eq!(audio["AudioObject.filepath"], "/music/\"Quoted\"\\.flac");
+    }
+
+
+    #[test]
+    fn albums_emptied_by_the_batch_are_dropped() {
+        let lib = Library {
+            name: "lib".into(),
+            path: "/music".into(),
+            duration_seconds: 0,
+            size_kilobytes: 0,
+        };
+        let mut retagged = tagged_track("mbid-t");
+        retagged.album = Some("Album".into());
+        let upsert = tracks_upsert(&[retagged], &lib);
+        let query = upsert["query"].as_str().unwrap();
+        let kept = r"(ka\d+) as var\(func: uid\(([^)]+)\)\) \{ MusicAlbum.track @filter\(not uid\(([^)]+)\)\)";
+        let kept = Regex::new(kept)
+            .unwrap()
+            .captures(query)
+            .unwrap();
+        // the album left by the recording and by dropped orphans, unless
+        // holding other recordings or joined again
+        assert_eq!(kept[2].split(", ").count(), 2);
+        assert_eq!(kept[3].split(", ").count(), 2);
+        let emptied = format!(
+            r"(ea\d+) as var\(func: uid\({}\)\) @filter\(not uid\({}al\) and not uid\(al\d+\)\)",
+            &kept[2], &kept[1]
+        );
+        let emptied = Regex::new(&emptied)
+            .unwrap()
+            .captures(query)
+            .unwrap();
+        assert!(upsert["mutations"][0]["delete"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "uid": format!("uid({})", &emptied[1]) })));
+    }
+
+
+    #[test]
+    fn quality_issues_are_listed() {
+        let mut flagged = tagged_track("mbid-t");
+        flagged.quality_issues = vec!["missingArtwork".into(), "missingAlbumMbid".into()];
+        let mut batch = UpsertBatch::default();
+        flagged.add_to(&mut batch, "uid(l0)");
+        tagged_track("mbid-u").add_to(&mut batch, "uid(l0)");
+        let upsert = batch.to_json();
+        let audio: Vec<&Value> = upsert["mutations"][0]["set"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|object| object["dgraph.type"] == "AudioObject")
+            .collect();
+        assert_eq!(audio[0]["AudioObject.qualityIssues"], json!(["missingArtwork", "missingAlbumMbid"]));
+        assert!(audio[1].get("AudioObject.qualityIssues").is_none());
+        // issues no longer found are removed
+        assert!(upsert["mutations"][0]["delete"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|object| object.get("AudioObject.qualityIssues").is_some_and(Value::is_null)));
+    }
+}
@@ -1,11 +1,10 @@
 use regex::Regex;
 use reqwest::Response;
 use serde::Deserialize;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::path::Path;
 
-use crate::CanariaError;
-
 mod dal;
 
 // MARK: Query result structs
@@ -14,19 +13,23 @@ mod dal;
 #[derive(Deserialize)]
 struct ResultExtensions {
     /// `{parsing,processing,encoding,assign_timestamp,total}_ns`
-    server_latency: HashMap<String, u32>,
-    /// Transaction stats: `start_ts`
-    txn: HashMap<String, u32>,
+    #[serde(default)]
+    server_latency: Option<Value>,
+    /// Transaction stats such as `start_ts`
+    #[serde(default)]
+    txn: Option<Value>,
     /// Result metrics per queried predicate plus `_total` field count
-    metrics: HashMap<String, HashMap<String, u32>>
+    #[serde(default)]
+    metrics: Option<Value>,
 }
 
 
-#[allow(dead_code)]
-#[derive(Deserialize)]
-struct ResultError {
-    message: String,
-    extensions: HashMap<String, String>
+#[derive(Debug, Deserialize)]
+pub struct ResultError {
+    pub message: String,
+    /// Error details such as `code`
+    #[serde(default)]
+    pub extensions: HashMap<String, Value>,
 }
 
 #[allow(dead_code)]
@@ -40,160 +43,285 @@ struct ResultData<T> {
     errors: Vec<ResultError>,
     
     /// Dgraph query metadata
-    extensions: ResultExtensions,
+    #[serde(default)]
+    extensions: Option<ResultExtensions>,
+}
+
+/// Any Dgraph response, as far as errors are concerned
+#[derive(Deserialize)]
+struct ResultErrors {
+    #[serde(default = "Vec::new")]
+    errors: Vec<ResultError>,
+}
+
+
+// MARK: Errors
+
+/// Failure interacting with DGraph backend
+#[derive(Debug)]
+pub enum DgraphError {
+    /// Request could not be sent or its response could not be read
+    Transport(reqwest::Error),
+    /// Non-successful HTTP status along with response body
+    Status(reqwest::StatusCode, String),
+    /// Errors reported by Dgraph in response body
+    Dgraph(Vec<ResultError>),
+    /// Response body not matching expected structure
+    Decode(serde_json::Error),
+    /// Response lacking an expected (named) result
+    MissingResult(String),
+    /// Request refused before being sent
+    Request(String),
+    /// Schema file could not be read
+    Schema(std::io::Error),
+}
+
+impl std::fmt::Display for DgraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Transport(err) => write!(f, "dgraph unreachable: {}", err),
+            Self::Status(status, body) => write!(f, "dgraph responded {}: {}", status, body),
+            Self::Dgraph(errors) => {
+                let messages: Vec<String> = errors
+                    .iter()
+                    .map(|e| match e.extensions.get("code") {
+                        Some(code) => format!("{} ({})", e.message, code),
+                        None => e.message.clone(),
+                    })
+                    .collect();
+                write!(f, "dgraph error: {}", messages.join("; "))
+            }
+            Self::Decode(err) => write!(f, "unexpected dgraph response: {}", err),
+            Self::MissingResult(name) => write!(f, "dgraph response lacks `{}` result", name),
+            Self::Request(message) => write!(f, "invalid dgraph request: {}", message),
+            Self::Schema(err) => write!(f, "dgraph schema unreadable: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DgraphError {}
+
+impl From<reqwest::Error> for DgraphError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Transport(err)
+    }
+}
+
+impl From<serde_json::Error> for DgraphError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Decode(err)
+    }
 }
 
 
 // MARK: Client
 
 /// Way to interact with DGraph backend
+#[derive(Clone)]
 pub struct DgraphClient {
     base_url: String,
+    /// HTTP connection pool, shared among clones
+    client: reqwest::Client,
 }
 
 impl DgraphClient {
     pub fn new(base_url: String) -> Self {
-        Self { base_url }
+        Self {
+            base_url,
+            client: reqwest::Client::new(),
+        }
     }
 
     // MARK: Data-strict manipulation methods
 
     /// Drop all database data
-    pub async fn drop_all(&self) -> Result<(), CanariaError> {
+    pub async fn drop_all(&self) -> Result<(), DgraphError> {
         let mut map = HashMap::new();
         map.insert("drop_all", true);
-        let client = reqwest::Client::new();
         let url = format!("{}{}", self.base_url, "/alter");
-        let res: Result<Response, CanariaError> = client
+        let res = self.client
             .post(url.as_str())
             .json(&map)
             .send()
-            .await
-            .map_err(|e| e.into());
-        let log = res?.text().await?;
+            .await?;
+        let log = response_body(res).await?;
         log::debug!("dropping database data: {:?}", log);
         Ok(())
     }
 
     /// (re)Defines dgraph internal (DQL) schema
-    pub async fn set_schema(&self, schemafile: &Path) -> Result<(), CanariaError> {
-        let schema = std::fs::read_to_string(schemafile)?;
-        let client = reqwest::Client::new();
+    pub async fn set_schema(&self, schemafile: &Path) -> Result<(), DgraphError> {
+        let schema = std::fs::read_to_string(schemafile).map_err(DgraphError::Schema)?;
         let url = format!("{}{}", self.base_url, "/alter");
-        let res: Result<Response, CanariaError> = client
+        let res = self.client
             .post(url.as_str())
             .body(schema)
             .send()
-            .await
-            .map_err(|e| e.into());
-        let log = res?.text().await?;
+            .await?;
+        let log = response_body(res).await?;
         log::debug!("setting schema: {:?}", log);
         Ok(())
     }
 
-    /// Executes a mutation resulting only success or error
-    pub async fn mutate(&self, dql: &str) -> Result<(), CanariaError> {
-        let client = reqwest::Client::new();
+    /// Executes a JSON mutation (or upsert block) resulting only success or error
+    pub async fn mutate(&self, mutation: &Value) -> Result<(), DgraphError> {
         let url = format!("{}{}", self.base_url, "/mutate?commitNow=true");
-        log::debug!("DQL Mutation: \n{}", dql);
-        let res: Result<Response, CanariaError> = client
+        log::debug!("JSON Mutation: \n{}", mutation);
+        let res = self.client
             .post(url.as_str())
-            .header(reqwest::header::CONTENT_TYPE, "application/rdf")
-            .body(dql.to_owned())
+            .json(mutation)
             .send()
-            .await
-            .map_err(|e| e.into());
-        let log = res?.text().await?;
+            .await?;
+        let log = response_body(res).await?;
         log::debug!("mutation response: {:?}", log);
         Ok(())
     }
 
-    /// Read-only query returning an "raw" database response
-    async fn query(&self, dql: String) -> Result<Response, CanariaError> {
+    /// Read-only query returning database response body. Query variables
+    /// (`$name`) are valued by `vars`
+    async fn query(&self, dql: String, vars: &HashMap<&str, String>) -> Result<String, DgraphError> {
         if dql.is_empty() {
-            return Err("empty query string".into());
+            return Err(DgraphError::Request("empty query string".into()));
         }
 
-        let client = reqwest::Client::new();
         let url = format!("{}{}", self.base_url, "/query");
-        log::debug!("DQL Query:\n{}", dql);
-        client
+        log::debug!("DQL Query:\n{}\nvariables: {:?}", dql, vars);
+        let res = self.client
             .post(url.as_str())
-            .header(reqwest::header::CONTENT_TYPE, "application/dql")
-            .body(dql)
+            .json(&serde_json::json!({ "query": dql, "variables": vars }))
             .send()
-            .await
-            .map_err(|e| e.into())
+            .await?;
+        response_body(res).await
     }
     
     /// Read-only query returning a single element
-    pub async fn query_single<T>(&self, dql: &str) -> Result<Option<T>, CanariaError>
+    pub async fn query_single<T>(
+        &self,
+        dql: &str,
+        vars: &HashMap<&str, String>,
+    ) -> Result<Option<T>, DgraphError>
     where T: for<'de> Deserialize<'de> + Clone {
-        let q_names = extract_query_names(dql);
-        if q_names.len() != 1 {
-            log::debug!("DQL Query:\n{}", dql);
-            return match q_names.len() {
-                0 => Err("could not extract query names from DQL".into()),
-                _ => Err("multiple query not supported".into())
-            }
-        }
-
-        let db_result = self.query(dql.into()).await?;
-        let mut results: ResultData<T> = db_result.json().await?;
-        let mut result = results.data.remove(&q_names[0]).unwrap();
+        let mut result = self.query_all(dql, vars).await?;
         if result.is_empty() {
             return Ok(None)
         }
         Ok(Some(result.swap_remove(0)))
     }
-}
-
-pub trait RDFable {
-    fn nqd<S: std::fmt::Display>(&self, subject: S, predicate: S) -> String;
-}
 
-impl<T> RDFable for Option<T> where T: std::fmt::Display {
-    fn nqd<S: std::fmt::Display>(&self, subject: S, predicate: S) -> String {
-        match self {
-            Some(object) => format!("{} {} \"{}\" .\n", subject, predicate, escape_chars(format!("{}", object))),
-            None => "".into()
+    /// Read-only query returning every element of its (single) result
+    pub async fn query_all<T>(
+        &self,
+        dql: &str,
+        vars: &HashMap<&str, String>,
+    ) -> Result<Vec<T>, DgraphError>
+    where T: for<'de> Deserialize<'de> {
+        let q_names = extract_query_names(dql);
+        if q_names.len() != 1 {
+            log::debug!("DQL Query:\n{}", dql);
+            return match q_names.len() {
+                0 => Err(DgraphError::Request("could not extract query names from DQL".into())),
+                _ => Err(DgraphError::Request("multiple query not supported".into()))
+            }
         }
-    }
-}
 
-// This shall be unified with `impl for Vec<PathBuf> for a generic type when PathBuf
-// implements Display or Into<String>
-impl RDFable for Vec<String> {
-    fn nqd<S: std::fmt::Display>(&self, subject: S, predicate: S) -> String {
-        let mut out: String = "".into();
-        for object in self {
-            out = format!("{}{} {} \"{}\" .\n", out, subject, predicate, escape_chars(object.into()))
-        }
-        out
+        let body = self.query(dql.into(), vars).await?;
+        let mut results: ResultData<T> = serde_json::from_str(&body)?;
+        results
+            .data
+            .remove(&q_names[0])
+            .ok_or_else(|| DgraphError::MissingResult(q_names[0].clone()))
     }
 }
 
-impl RDFable for Vec<std::path::PathBuf> {
-    fn nqd<S: std::fmt::Display>(&self, subject: S, predicate: S) -> String {
-        let mut out: String = "".into();
-        for object in self {
-            out = format!("{}{} {} \"{}\" .\n", out, subject, predicate, object.display())
-        }
-        out
+/// Provides response body of a successful request, mapping HTTP status and
+/// Dgraph reported errors into `DgraphError`
+async fn response_body(res: Response) -> Result<String, DgraphError> {
+    let status = res.status();
+    let body = res.text().await?;
+    if !status.is_success() {
+        return Err(DgraphError::Status(status, body));
+    }
+    let reported: ResultErrors = serde_json::from_str(&body)?;
+    if !reported.errors.is_empty() {
+        return Err(DgraphError::Dgraph(reported.errors));
     }
+    Ok(body)
 }
 
 /// Inform query name used in DQL query. Typically used for result extraction
 fn extract_query_names(dql: &str) -> Vec<String> {
+    // query header (e.g. `query q($name: string)`) is not a query block
+    let header = Regex::new(r#"^\s*query\s+\w+\s*\([^)]*\)"#).expect("bogus regexp");
+    let dql = header.replace(dql, "");
     let regex = Regex::new(r#"\s*(\w+)\s*\(.*\)[@\s\w]*\{"#).expect("bogus regexp");
     let mut out: Vec<String> = Vec::new();
-    for block in regex.captures_iter(dql) {
+    for block in regex.captures_iter(&dql) {
         if &block[1] == "var" { continue }
         out.insert(out.len(), block[1].into());
     }
     out
 }
 
-fn escape_chars(value: String) -> String {
-    value.replace("\"", r#"\""#)
-}
\ No newline at end of file
+/// Encodes a value as a DQL string literal, for query blocks not accepting
+/// variables (e.g. upsert queries)
+fn dql_string(value: &str) -> String {
+    Value::from(value).to_string()
+}
+
+/// Removes null valued predicates from a JSON mutation object
+fn set_object(object: Value) -> Value {
+    match object {
+        Value::Object(map) => map.into_iter().filter(|(_, value)| !value.is_null()).collect(),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dql_strings_are_escaped() {
+        assert_eq!(dql_string("plain"), "\"plain\"");
+        assert_eq!(dql_string("say \"hi\""), r#""say \"hi\"""#);
+        assert_eq!(dql_string("ends with \\"), r#""ends with \\""#);
+        assert_eq!(dql_string("two\nlines"), r#""two\nlines""#);
+        for value in ["say \"hi\"", "ends with \\", "two\nlines\ttabbed"] {
+            assert_eq!(serde_json::from_str::<String>(&dql_string(value)).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn set_objects_keep_values_but_nulls() {
+        let object = serde_json::json!({
+            "uid": "_:t",
+            "CreativeWork.title": "ends with \\",
+            "CreativeWork.artist": "say \"hi\"\n",
+            "MusicRecording.lyrics": null,
+        });
+        assert_eq!(
+            set_object(object),
+            serde_json::json!({
+                "uid": "_:t",
+                "CreativeWork.title": "ends with \\",
+                "CreativeWork.artist": "say \"hi\"\n",
+            })
+        );
+    }
+
+
+    #[test]
+    fn query_metadata_does_not_fail_results() {
+        let body = r#"{
+            "data": { "q": [{ "uid": "0x1" }] },
+            "extensions": {
+                "server_latency": { "processing_ns": 5000000000, "total_ns": 5000000001 },
+                "txn": { "start_ts": 12, "hash": "abc" }
+            }
+        }"#;
+        let results: ResultData<Value> = serde_json::from_str(body).unwrap();
+        assert_eq!(results.data["q"].len(), 1);
+        let results: ResultData<Value> = serde_json::from_str(r#"{ "data": { "q": [] } }"#).unwrap();
+        assert!(results.extensions.is_none());
+    }
+}
@@ -1,7 +1,6 @@
-use config;
-use log;
-use pretty_env_logger;
+use std::convert::TryFrom;
 use std::path::Path;
+use std::time::Duration;
 
 mod dgraph;
 mod music;
@@ -31,13 +30,113 @@ async fn main() -> Result<(), CanariaError> {
         .iter_mut()
         .map(|v| v.clone().into_string().unwrap_or("".into()))
         .collect();
-    music::library::Library::new(
+    let tag_separators: Vec<String> = match settings.get_array("tag_separators") {
+        Ok(separators) => separators
+            .into_iter()
+            .filter_map(|v| v.into_string().ok())
+            .collect(),
+        Err(_) => vec![";".into(), "/".into()],
+    };
+    let cover_patterns: Vec<String> = match settings.get_array("cover_patterns") {
+        Ok(patterns) => patterns
+            .into_iter()
+            .filter_map(|v| v.into_string().ok())
+            .collect(),
+        Err(_) => ["cover.*", "folder.*", "front.*", "albumart*"]
+            .iter()
+            .map(|pattern| pattern.to_string())
+            .collect(),
+    };
+    let mut path_templates = Vec::new();
+    for template in settings.get_array("path_templates").unwrap_or_default() {
+        path_templates.push(music::template::PathTemplate::new(&template.into_string()?)?);
+    }
+    path_templates.extend(music::template::PathTemplate::filename_patterns());
+    let mut quality = music::quality::QualityPolicy::default();
+    if let Ok(fields) = settings.get_array("quality_required") {
+        quality.required = fields
+            .into_iter()
+            .map(|field| field.into_string()?.parse())
+            .collect::<Result<_, CanariaError>>()?;
+    }
+    if let Ok(fields) = settings.get_array("quality_recommended") {
+        quality.recommended = fields
+            .into_iter()
+            .map(|field| field.into_string()?.parse())
+            .collect::<Result<_, CanariaError>>()?;
+    }
+    quality.min_duration_seconds = u32::try_from(settings.get_int("min_duration_seconds").unwrap_or(0))
+        .map_err(|_| "min_duration_seconds must not be negative")?;
+    quality.min_bitrate = u32::try_from(settings.get_int("min_bitrate").unwrap_or(0))
+        .map_err(|_| "min_bitrate must not be negative")?;
+    quality.record_rejected = settings.get_bool("record_rejected").unwrap_or(false);
+    let mut tag_mapping = std::collections::HashMap::new();
+    for (key, field) in settings.get_table("tag_mapping").unwrap_or_default() {
+        let field = field.into_string()?;
+        if !music::metadata::TAG_FIELDS.contains(&field.as_str()) {
+            return Err(format!("unknown field `{}` mapped from tag `{}`", field, key).into());
+        }
+        tag_mapping.insert(key.to_lowercase(), field);
+    }
+    let options = music::library::IngestOptions {
+        music_ignore_list,
+        incremental: settings.get_bool("incremental_ingestion").unwrap_or(true),
+        missing_files: settings
+            .get_string("missing_files")
+            .unwrap_or_else(|_| "mark".into())
+            .parse()?,
+        ingest_workers: match settings.get_int("ingest_workers") {
+            Ok(workers) => usize::try_from(workers).map_err(|_| "ingest_workers must not be negative")?,
+            Err(_) => std::thread::available_parallelism()
+                .map(|workers| workers.get())
+                .unwrap_or(1),
+        },
+        dgraph_max_inflight: usize::try_from(settings.get_int("dgraph_max_inflight").unwrap_or(4))
+            .map_err(|_| "dgraph_max_inflight must not be negative")?,
+        dgraph_batch_size: usize::try_from(settings.get_int("dgraph_batch_size").unwrap_or(50))
+            .map_err(|_| "dgraph_batch_size must not be negative")?,
+        metadata: music::metadata::MetadataOptions {
+            tag_separators,
+            image_cache: settings
+                .get_string("image_cache_path")
+                .unwrap_or_else(|_| "images".into())
+                .into(),
+            cover_patterns,
+            path_templates,
+            quality,
+            tag_mapping,
+        },
+    };
+    // started beforehand, so changes made during ingestion are caught up on
+    let watcher = if settings.get_bool("watch").unwrap_or(false) {
+        Some(music::watch::LibraryWatcher::new(Path::new(
+            &settings.get_string("music_library_path")?,
+        ))?)
+    } else {
+        None
+    };
+    let lib = music::library::Library::new(
         settings.get_string("music_library_path")?,
         settings.get_string("music_library_name")?,
         &db_client,
-        music_ignore_list,
+        &options,
     )
     .await?;
+    log::info!(
+        "library `{}` ({}) ingested: {} seconds, {} kilobytes",
+        lib.name,
+        lib.path.display(),
+        lib.duration_seconds,
+        lib.size_kilobytes
+    );
+
+    if let Some(watcher) = watcher {
+        let debounce = u64::try_from(settings.get_int("watch_debounce_ms").unwrap_or(2000))
+            .map_err(|_| "watch_debounce_ms must not be negative")?;
+        watcher
+            .watch(lib, &db_client, &options, Duration::from_millis(debounce))
+            .await?;
+    }
 
     Ok(())
 }
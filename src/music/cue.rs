@@ -0,0 +1,301 @@
+use std::path::Path;
+
+/// A CUE sheet, describing tracks laid out in one or many audio files
+#[derive(Clone, Debug, Default)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// `REM GENRE` comment
+    pub genre: Option<String>,
+    /// `REM DATE` comment
+    pub date: Option<String>,
+    pub files: Vec<CueFile>,
+}
+
+/// Audio file referenced by a CUE sheet, along with its tracks
+#[derive(Clone, Debug, Default)]
+pub struct CueFile {
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+/// A track laid out in an audio file
+#[derive(Clone, Debug, Default)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Pregap start (`INDEX 00`) in milliseconds from file start
+    pub pregap_ms: Option<u32>,
+    /// Track start (`INDEX 01`) in milliseconds from file start
+    pub start_ms: u32,
+    /// Track end in milliseconds from file start, up to file end if missing
+    pub end_ms: Option<u32>,
+}
+
+impl CueSheet {
+    /// Parses CUE sheet text. Data tracks and unknown commands are skipped
+    pub fn parse(text: &str) -> Self {
+        let mut sheet = Self::default();
+        let mut track: Option<CueTrack> = None;
+        for line in text.trim_start_matches('\u{feff}').lines() {
+            let (command, args) = split_command(line.trim());
+            match command.to_uppercase().as_str() {
+                "REM" => {
+                    let (key, value) = split_command(args);
+                    match key.to_uppercase().as_str() {
+                        "GENRE" => sheet.genre = Some(unquote(value)),
+                        "DATE" => sheet.date = Some(unquote(value)),
+                        _ => (),
+                    }
+                }
+                "FILE" => {
+                    sheet.close_track(track.take());
+                    sheet.files.push(CueFile {
+                        name: file_name(args),
+                        tracks: Vec::new(),
+                    });
+                }
+                "TRACK" => {
+                    sheet.close_track(track.take());
+                    let (number, kind) = split_command(args);
+                    if kind.eq_ignore_ascii_case("AUDIO") {
+                        track = number.parse().ok().map(|number| CueTrack {
+                            number,
+                            ..Default::default()
+                        });
+                    }
+                }
+                "TITLE" => match &mut track {
+                    Some(track) => track.title = Some(unquote(args)),
+                    None => sheet.title = Some(unquote(args)),
+                },
+                "PERFORMER" => match &mut track {
+                    Some(track) => track.performer = Some(unquote(args)),
+                    None => sheet.performer = Some(unquote(args)),
+                },
+                "INDEX" => {
+                    let (index, time) = split_command(args);
+                    if let (Some(track), Some(time_ms)) = (&mut track, parse_time(time)) {
+                        match index.parse::<u32>() {
+                            Ok(0) => track.pregap_ms = Some(time_ms),
+                            Ok(1) => track.start_ms = time_ms,
+                            _ => (),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        sheet.close_track(track.take());
+
+        // tracks end where the next one (pregap included) starts
+        for file in &mut sheet.files {
+            let starts: Vec<u32> = file
+                .tracks
+                .iter()
+                .map(|track| track.pregap_ms.unwrap_or(track.start_ms))
+                .collect();
+            for (track, next_start) in file.tracks.iter_mut().zip(starts.into_iter().skip(1)) {
+                track.end_ms = Some(next_start);
+            }
+        }
+        sheet
+    }
+
+    fn close_track(&mut self, track: Option<CueTrack>) {
+        if let (Some(track), Some(file)) = (track, self.files.last_mut()) {
+            file.tracks.push(track);
+        }
+    }
+
+    /// Tracks laid out in an audio file: those of the sheet file entry of the
+    /// same name (or stem, as rips often get transcoded), or of the only entry
+    pub fn tracks_of(&self, file: &Path) -> Vec<CueTrack> {
+        let same = |a: Option<&std::ffi::OsStr>, b: Option<&std::ffi::OsStr>| {
+            a.is_some() && a.map(|a| a.to_ascii_lowercase()) == b.map(|b| b.to_ascii_lowercase())
+        };
+        let entry = self
+            .files
+            .iter()
+            .find(|entry| same(Path::new(&entry.name).file_name(), file.file_name()))
+            .or_else(|| {
+                self.files
+                    .iter()
+                    .find(|entry| same(Path::new(&entry.name).file_stem(), file.file_stem()))
+            })
+            .or_else(|| self.files.first().filter(|_| self.files.len() == 1));
+        entry.map(|entry| entry.tracks.clone()).unwrap_or_default()
+    }
+}
+
+/// Text of a CUE sheet lying next to an audio file: the one of the same
+/// name, or any other referencing the audio file
+pub fn find_sidecar(file: &Path) -> Option<String> {
+    let same_name = ["cue", "CUE"]
+        .iter()
+        .map(|extension| file.with_extension(extension))
+        .find(|sidecar| sidecar.is_file());
+    if let Some(sidecar) = same_name {
+        return read_text(&sidecar);
+    }
+    let file_name = file.file_name()?.to_string_lossy().to_lowercase();
+    std::fs::read_dir(file.parent()?)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("cue"))
+        })
+        .filter_map(|path| read_text(&path))
+        .find(|text| {
+            CueSheet::parse(text)
+                .files
+                .iter()
+                .any(|entry| entry.name.to_lowercase().ends_with(&file_name))
+        })
+}
+
+/// Latest modification time of the CUE sheets lying in `dir` (0 if none),
+/// as any of them may describe the audio files next to it
+pub fn sidecars_modified(dir: &Path) -> u64 {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("cue"))
+        })
+        .filter_map(|path| path.metadata().ok())
+        .map(|metadata| super::metadata::modification_time(&metadata))
+        .max()
+        .unwrap_or(0)
+}
+
+/// CUE sheet file content, read as Latin-1 when not UTF-8 (as written by
+/// many ripping tools)
+fn read_text(path: &Path) -> Option<String> {
+    let data = std::fs::read(path).ok()?;
+    Some(String::from_utf8(data).unwrap_or_else(|err| {
+        err.into_bytes().iter().map(|&b| b as char).collect()
+    }))
+}
+
+/// Splits a line into its first word and the rest
+fn split_command(line: &str) -> (&str, &str) {
+    match line.split_once(char::is_whitespace) {
+        Some((command, args)) => (command, args.trim()),
+        None => (line, ""),
+    }
+}
+
+/// Value with its surrounding quotes removed
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+        .to_string()
+}
+
+/// File name of a `FILE` command, its type (e.g. `WAVE`) excluded
+fn file_name(args: &str) -> String {
+    match args.strip_prefix('"').and_then(|args| args.split_once('"')) {
+        Some((name, _)) => name.to_string(),
+        None => args.rsplit_once(char::is_whitespace).map_or(args, |(name, _)| name).to_string(),
+    }
+}
+
+/// Milliseconds of a `mm:ss:ff` time (75 frames per second)
+fn parse_time(time: &str) -> Option<u32> {
+    let mut parts = time.split(':').map(|part| part.trim().parse::<u32>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    minutes
+        .checked_mul(60)?
+        .checked_add(seconds)?
+        .checked_mul(1000)?
+        .checked_add(frames.checked_mul(1000)? / 75)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = "\u{feff}REM GENRE Jazz\n\
+        REM DATE 1959\n\
+        PERFORMER \"Miles Davis\"\n\
+        TITLE \"Kind of Blue\"\n\
+        FILE \"Kind of Blue.wav\" WAVE\n  \
+          TRACK 01 AUDIO\n    \
+            TITLE \"So What\"\n    \
+            ISRC USSM15900113\n    \
+            INDEX 01 00:00:00\n  \
+          TRACK 02 AUDIO\n    \
+            TITLE \"Freddie Freeloader\"\n    \
+            PERFORMER \"Miles Davis Sextet\"\n    \
+            INDEX 00 09:20:00\n    \
+            INDEX 01 09:22:37\n  \
+          TRACK 03 MODE1/2352\n    \
+            INDEX 01 19:00:00\n";
+
+    #[test]
+    fn sheet_and_track_fields() {
+        let sheet = CueSheet::parse(SHEET);
+        assert_eq!(sheet.title.as_deref(), Some("Kind of Blue"));
+        assert_eq!(sheet.performer.as_deref(), Some("Miles Davis"));
+        assert_eq!(sheet.genre.as_deref(), Some("Jazz"));
+        assert_eq!(sheet.date.as_deref(), Some("1959"));
+        assert_eq!(sheet.files.len(), 1);
+        assert_eq!(sheet.files[0].name, "Kind of Blue.wav");
+
+        // the data track is skipped
+        let tracks = &sheet.files[0].tracks;
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].title.as_deref(), Some("So What"));
+        assert_eq!(tracks[0].performer, None);
+        assert_eq!(tracks[1].performer.as_deref(), Some("Miles Davis Sextet"));
+        assert_eq!(tracks[1].pregap_ms, Some(560_000));
+        assert_eq!(tracks[1].start_ms, 562_493);
+    }
+
+    #[test]
+    fn tracks_end_at_next_pregap() {
+        let tracks = &CueSheet::parse(SHEET).files[0].tracks;
+        assert_eq!(tracks[0].end_ms, Some(560_000));
+        assert_eq!(tracks[1].end_ms, None);
+    }
+
+    #[test]
+    fn tracks_of_matching_file_entry() {
+        let sheet = CueSheet::parse(
+            "FILE \"disc1.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+             FILE disc2.flac WAVE\nTRACK 02 AUDIO\nINDEX 01 00:00:00\nTRACK 03 AUDIO\nINDEX 01 03:00:00\n",
+        );
+        assert_eq!(sheet.files[1].name, "disc2.flac");
+        assert_eq!(sheet.tracks_of(Path::new("/music/DISC2.FLAC")).len(), 2);
+        // transcoded rips are matched by stem
+        assert_eq!(sheet.tracks_of(Path::new("/music/disc1.flac")).len(), 1);
+        assert!(sheet.tracks_of(Path::new("/music/disc3.flac")).is_empty());
+    }
+
+    #[test]
+    fn tracks_of_single_file_entry() {
+        let sheet = CueSheet::parse(SHEET);
+        assert_eq!(sheet.tracks_of(Path::new("/music/CDImage.flac")).len(), 2);
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("01:02:00"), Some(62_000));
+        assert_eq!(parse_time("00:00:75"), Some(1_000));
+        assert_eq!(parse_time("00:01"), None);
+        assert_eq!(parse_time("aa:00:00"), None);
+        assert_eq!(parse_time("4294967295:00:00"), None);
+        assert_eq!(parse_time("00:4294967295:00"), None);
+        assert_eq!(parse_time("00:00:4294967295"), None);
+    }
+}
@@ -1,8 +1,17 @@
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
 use walkdir::WalkDir;
 
+use super::cue;
+use super::lyrics;
+use super::metadata::{modification_time, MetadataOptions};
+use super::picture;
 use super::track::Track;
+use crate::dgraph::DgraphError;
 use crate::CanariaError;
 use crate::DgraphClient;
 
@@ -22,6 +31,132 @@ pub struct Library {
     pub size_kilobytes: u64,
 }
 
+/// Audio file state recorded on its last ingestion
+#[derive(Clone, Deserialize)]
+pub struct FileStamp {
+    /// Database node reference
+    pub uid: String,
+
+    pub file_path: PathBuf,
+
+    /// File size in kilobytes
+    #[serde(default)]
+    pub file_size: u64,
+
+    /// File modification time (seconds since UNIX epoch)
+    #[serde(default)]
+    pub file_modified: u64,
+
+    /// SHA-256 digest (hex) of the audio payload
+    pub content_hash: Option<String>,
+
+    /// Whether file was already missing on a previous reconciliation
+    #[serde(default)]
+    pub missing: bool,
+
+    /// Modification time of the folder image found on ingestion (0 if none)
+    #[serde(default)]
+    pub cover_modified: u64,
+
+    /// Latest modification time of the CUE sheets lying next to the file on
+    /// ingestion (0 if none)
+    #[serde(default)]
+    pub cue_modified: u64,
+
+    /// Modification time of the lyrics sidecar found on ingestion (0 if none)
+    #[serde(default)]
+    pub lyrics_modified: u64,
+}
+
+impl FileStamp {
+    /// Whether file metadata still matches this stamp
+    pub fn matches(&self, metadata: &std::fs::Metadata) -> bool {
+        self.file_size == metadata.len() / 1024
+            && self.file_modified == modification_time(metadata)
+    }
+}
+
+/// What to do with database files no longer found in the filesystem
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissingFilesPolicy {
+    /// Remove audio file (and recordings left without audio) from database
+    Delete,
+    /// Keep audio file flagged as missing
+    Mark,
+}
+
+impl FromStr for MissingFilesPolicy {
+    type Err = CanariaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "delete" => Ok(Self::Delete),
+            "mark" => Ok(Self::Mark),
+            _ => Err(format!("unknown missing files policy `{}`", s).into()),
+        }
+    }
+}
+
+/// Library ingestion parameters
+#[derive(Clone)]
+pub struct IngestOptions {
+    /// File extensions (lowercase) never ingested
+    pub music_ignore_list: Vec<String>,
+
+    /// Skip files unchanged since their last ingestion
+    pub incremental: bool,
+
+    /// What to do with database files no longer found in filesystem
+    pub missing_files: MissingFilesPolicy,
+
+    /// Number of files probed simultaneously
+    pub ingest_workers: usize,
+
+    /// Maximum number of database mutations issued simultaneously
+    pub dgraph_max_inflight: usize,
+
+    /// Number of tracks sent to database per mutation
+    pub dgraph_batch_size: usize,
+
+    /// Metadata extraction parameters
+    pub metadata: MetadataOptions,
+}
+
+impl IngestOptions {
+    /// Whether a file extension is on the ignore list
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let ext = path
+            .extension()
+            .map(|x| x.to_str().unwrap_or(""))
+            .unwrap_or("")
+            .to_lowercase();
+        self.music_ignore_list.contains(&ext)
+    }
+}
+
+/// Files accountability of an ingestion
+#[derive(Debug, Default)]
+pub struct IngestReport {
+    /// Files sent to database
+    pub ingested: usize,
+    /// Files skipped as unchanged since their last ingestion
+    pub unchanged: usize,
+    /// Files not suitable for ingestion (e.g. not audio or poor metadata)
+    pub ignored: usize,
+    /// Files suitable but refused by database
+    pub failed: usize,
+}
+
+impl std::fmt::Display for IngestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ingested, {} unchanged, {} ignored, {} failed",
+            self.ingested, self.unchanged, self.ignored, self.failed
+        )
+    }
+}
+
 impl Library {
     /// Import library -> tracks in database fs returning Library struct with
     /// size and duration accountability
@@ -29,7 +164,7 @@ impl Library {
         root: String,
         name: String,
         db: &DgraphClient,
-        music_ignore_list: Vec<String>,
+        options: &IngestOptions,
     ) -> Result<Self, CanariaError> {
         let path = PathBuf::from(root.clone()).canonicalize();
         if let Err(err) = path {
@@ -42,32 +177,340 @@ impl Library {
             return Err("library path is not a directory".into());
         }
 
-        let mut lib = db.get_library(name, path.clone()).await?;
+        let mut lib = db.get_library(name.clone(), path.clone()).await?;
+        let report = lib.ingest(db, vec![path.clone()], options).await?;
+        if report.failed > 0 {
+            log::error!("library `{}` files: {}", lib.name, report);
+            if report.ingested == 0 {
+                return Err("every track failed database ingestion".into());
+            }
+        } else {
+            log::info!("library `{}` files: {}", lib.name, report);
+        }
+
+        // refresh size and duration accountability after ingestion
+        Ok(db.get_library(name, path).await?)
+    }
+
+    /// Ingests files found under `paths` (either files or directories), then
+    /// reconciles database files no longer found in the filesystem
+    pub async fn ingest(
+        &mut self,
+        db: &DgraphClient,
+        paths: Vec<PathBuf>,
+        options: &IngestOptions,
+    ) -> Result<IngestReport, DgraphError> {
+        let stamps = db.get_library_files(self).await?;
+        self.ingest_with(db, paths, &stamps, options).await
+    }
+
+    /// Ingests files found under `changed` paths, then reconciles `removed`
+    /// ones, only fetching the stamps of the files involved. Removed paths
+    /// not known as audio files (e.g. directories) are reconciled against
+    /// every library file
+    pub async fn ingest_changes(
+        &mut self,
+        db: &DgraphClient,
+        changed: Vec<PathBuf>,
+        removed: Vec<PathBuf>,
+        options: &IngestOptions,
+    ) -> Result<IngestReport, DgraphError> {
+        let mut files: Vec<PathBuf> = changed
+            .iter()
+            .flat_map(WalkDir::new)
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| entry.path().canonicalize().ok())
+            .collect();
+        files.extend(removed.iter().cloned());
+        let mut stamps = db.get_files(&files).await?;
+        if removed.iter().any(|path| !stamps.contains_key(path)) {
+            log::debug!("unknown paths removed, reconciling every library file");
+            stamps = db.get_library_files(self).await?;
+        }
+        self.ingest_with(db, changed, &stamps, options).await
+    }
+
+    /// Ingests files found under `paths`, given the stamps of (at least)
+    /// these files and of those to be reconciled
+    async fn ingest_with(
+        &mut self,
+        db: &DgraphClient,
+        paths: Vec<PathBuf>,
+        stamps: &HashMap<PathBuf, FileStamp>,
+        options: &IngestOptions,
+    ) -> Result<IngestReport, DgraphError> {
+        let mut seen: HashSet<PathBuf> = HashSet::new();
+        // folder image modification time by directory
+        let mut covers: HashMap<PathBuf, u64> = HashMap::new();
+        // latest CUE sheet modification time by directory
+        let mut cues: HashMap<PathBuf, u64> = HashMap::new();
+        let mut pipeline = Pipeline::new(db, self, options);
 
-        for entry in WalkDir::new(path) {
-            let entry = entry.unwrap();
+        for entry in paths.iter().flat_map(WalkDir::new) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    log::warn!("{}", err);
+                    continue;
+                }
+            };
             let entry_path = entry.path();
+            if picture::sidecar_rank(entry_path, &options.metadata.cover_patterns).is_some() {
+                continue;
+            }
 
-            let ext = entry_path
-                .extension()
-                .map(|x| x.to_str().unwrap_or(""))
-                .unwrap_or("")
-                .to_lowercase();
-            if music_ignore_list.contains(&ext) {
+            if options.is_ignored(entry_path) {
                 continue;
             }
-            let metadata = entry_path.metadata()?;
+            let (metadata, file_path) = match (entry_path.metadata(), entry_path.canonicalize()) {
+                (Ok(metadata), Ok(file_path)) => (metadata, file_path),
+                (Err(err), _) | (_, Err(err)) => {
+                    log::warn!("{}: {}", entry_path.display(), err);
+                    continue;
+                }
+            };
             if metadata.is_file() {
-                log::info!("importing {}", entry_path.display());
-                match Track::from_file(entry_path) {
-                    Ok(track) => if let Err(err) = db.update_track(track, & mut lib).await {
-                        log::error!("{}", err);
-                    },
-                    Err(err) => log::info!("ignoring {}: {}", entry_path.display(), err),
+                let dir = file_path.parent().unwrap_or(&file_path).to_path_buf();
+                let cue_modified = *cues
+                    .entry(dir.clone())
+                    .or_insert_with_key(|dir| cue::sidecars_modified(dir));
+                let cover_modified = *covers.entry(dir).or_insert_with_key(|dir| {
+                    picture::find_sidecar(dir, &options.metadata.cover_patterns)
+                        .map(|(_, modified)| modified)
+                        .unwrap_or(0)
+                });
+                let unchanged = stamps.get(&file_path).is_some_and(|stamp| {
+                    !stamp.missing
+                        && stamp.matches(&metadata)
+                        && stamp.cover_modified == cover_modified
+                        && stamp.cue_modified == cue_modified
+                        && stamp.lyrics_modified == lyrics::sidecar_modified(&file_path)
+                });
+                // walked twice when both a directory and its files are given
+                if !seen.insert(file_path.clone()) {
+                    continue;
+                }
+                if options.incremental && unchanged {
+                    log::debug!("skipping unchanged {}", entry_path.display());
+                    pipeline.report.unchanged += 1;
+                    continue;
                 }
+
+                pipeline.probe(file_path).await;
+            }
+        }
+        let (ingested, report) = pipeline.finish().await;
+
+        self.reconcile(db, stamps, &seen, &ingested, options.missing_files).await?;
+        Ok(report)
+    }
+
+    /// Handles database audio files no longer found under library path:
+    /// files whose audio content got ingested elsewhere are considered moved
+    /// (the old file node is dropped in favor of the new one) while others
+    /// are handled according to `policy`
+    async fn reconcile(
+        &self,
+        db: &DgraphClient,
+        stamps: &HashMap<PathBuf, FileStamp>,
+        seen: &HashSet<PathBuf>,
+        ingested: &HashMap<String, PathBuf>,
+        policy: MissingFilesPolicy,
+    ) -> Result<(), DgraphError> {
+        for (file_path, stamp) in stamps {
+            if !file_path.starts_with(&self.path) || seen.contains(file_path) || file_path.exists() {
+                continue;
+            }
+            let moved_to = stamp.content_hash.as_ref().and_then(|hash| ingested.get(hash));
+            if let Some(new_path) = moved_to {
+                log::info!("moved {} -> {}", file_path.display(), new_path.display());
+                db.delete_audio_object(&stamp.uid, self).await?;
+            } else if policy == MissingFilesPolicy::Delete {
+                log::info!("deleting missing {}", file_path.display());
+                db.delete_audio_object(&stamp.uid, self).await?;
+            } else if !stamp.missing {
+                log::info!("marking missing {}", file_path.display());
+                db.mark_audio_object_missing(&stamp.uid).await?;
             }
         }
+        Ok(())
+    }
+}
+
+/// Single file ingestion result
+enum FileOutcome {
+    /// File ingested, providing its audio content hash
+    Ingested(Option<String>),
+    /// File suitable but refused by database
+    Failed(DgraphError),
+}
+
+/// Ingestion pipeline events
+enum Progress {
+    /// File probed (or not suitable for ingestion)
+    Probed(PathBuf, Result<Box<Track>, CanariaError>),
+    /// Batch of files sent to database
+    Committed(Vec<(PathBuf, FileOutcome)>),
+}
+
+/// Ingestion pipeline: files are probed by a bounded number of blocking
+/// tasks, then gathered into batches committed concurrently to database
+struct Pipeline {
+    db: DgraphClient,
+    lib: Arc<Library>,
+    metadata: Arc<MetadataOptions>,
+    workers: usize,
+    batch_size: usize,
+    mutation_permits: Arc<Semaphore>,
+    tx: mpsc::UnboundedSender<Progress>,
+    rx: mpsc::UnboundedReceiver<Progress>,
+    /// Probing tasks running
+    probing: usize,
+    /// Mutation tasks running
+    committing: usize,
+    /// Probed tracks waiting for a batch to be committed
+    batch: Vec<(PathBuf, Track)>,
+    /// Ingested file paths indexed by audio content hash
+    ingested: HashMap<String, PathBuf>,
+    report: IngestReport,
+}
+
+impl Pipeline {
+    fn new(db: &DgraphClient, lib: &Library, options: &IngestOptions) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            db: db.clone(),
+            lib: Arc::new(lib.clone()),
+            metadata: Arc::new(options.metadata.clone()),
+            workers: options.ingest_workers.max(1),
+            batch_size: options.dgraph_batch_size.max(1),
+            mutation_permits: Arc::new(Semaphore::new(options.dgraph_max_inflight.max(1))),
+            tx,
+            rx,
+            probing: 0,
+            committing: 0,
+            batch: Vec::new(),
+            ingested: HashMap::new(),
+            report: IngestReport::default(),
+        }
+    }
+
+    /// Probes a file on the blocking thread pool, waiting for a free worker
+    async fn probe(&mut self, file_path: PathBuf) {
+        while self.probing >= self.workers {
+            self.step().await;
+        }
+        log::info!("importing {}", file_path.display());
+        let (tx, metadata) = (self.tx.clone(), self.metadata.clone());
+        tokio::task::spawn_blocking(move || {
+            let probed = std::panic::catch_unwind(|| Track::from_file(&file_path, &metadata))
+                .unwrap_or_else(|_| Err("probing panicked".into()))
+                .map(Box::new);
+            let _ = tx.send(Progress::Probed(file_path, probed));
+        });
+        self.probing += 1;
+    }
+
+    /// Sends gathered tracks to database, waiting for a mutation slot
+    async fn commit(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let batch = std::mem::take(&mut self.batch);
+        let permit = self.mutation_permits.clone().acquire_owned().await;
+        let (db, lib, tx) = (self.db.clone(), self.lib.clone(), self.tx.clone());
+        tokio::spawn(async move {
+            let outcomes = commit_batch(&db, &lib, batch).await;
+            drop(permit);
+            let _ = tx.send(Progress::Committed(outcomes));
+        });
+        self.committing += 1;
+    }
+
+    /// Handles next pipeline event
+    async fn step(&mut self) {
+        match self.rx.recv().await {
+            Some(Progress::Probed(file_path, probed)) => {
+                self.probing -= 1;
+                match probed {
+                    Ok(track) => {
+                        self.batch.push((file_path, *track));
+                        if self.batch.len() >= self.batch_size {
+                            self.commit().await;
+                        }
+                    }
+                    Err(err) => {
+                        log::info!("ignoring {}: {}", file_path.display(), err);
+                        self.report.ignored += 1;
+                    }
+                }
+            }
+            Some(Progress::Committed(outcomes)) => {
+                self.committing -= 1;
+                for (file_path, outcome) in outcomes {
+                    match outcome {
+                        FileOutcome::Ingested(hash) => {
+                            self.report.ingested += 1;
+                            if let Some(hash) = hash {
+                                self.ingested.insert(hash, file_path);
+                            }
+                        }
+                        FileOutcome::Failed(err) => {
+                            log::error!("{}: {}", file_path.display(), err);
+                            self.report.failed += 1;
+                        }
+                    }
+                }
+            }
+            None => (),
+        }
+    }
+
+    /// Awaits every file to be ingested, providing ingested file paths
+    /// indexed by audio content hash along with files accountability
+    async fn finish(mut self) -> (HashMap<String, PathBuf>, IngestReport) {
+        while self.probing > 0 {
+            self.step().await;
+        }
+        self.commit().await;
+        while self.committing > 0 {
+            self.step().await;
+        }
+        (self.ingested, self.report)
+    }
+}
+
+/// Commits a batch of tracks, retrying each track on its own when the batch
+/// fails so a single bad file does not lose the whole batch
+async fn commit_batch(
+    db: &DgraphClient,
+    lib: &Library,
+    batch: Vec<(PathBuf, Track)>,
+) -> Vec<(PathBuf, FileOutcome)> {
+    let tracks: Vec<Track> = batch.iter().map(|(_, track)| track.clone()).collect();
+    let err = match db.update_tracks(&tracks, lib).await {
+        Ok(()) => {
+            return batch
+                .into_iter()
+                .map(|(file_path, track)| (file_path, FileOutcome::Ingested(track.content_hash)))
+                .collect()
+        }
+        Err(err) => err,
+    };
+    if batch.len() == 1 {
+        let (file_path, _) = batch.into_iter().next().unwrap();
+        return vec![(file_path, FileOutcome::Failed(err))];
+    }
 
-        Ok(lib)
+    log::warn!("batch of {} tracks failed, retrying one by one: {}", batch.len(), err);
+    let mut outcomes = Vec::with_capacity(batch.len());
+    for (file_path, track) in batch {
+        let outcome = match db.update_tracks(std::slice::from_ref(&track), lib).await {
+            Ok(()) => FileOutcome::Ingested(track.content_hash),
+            Err(err) => FileOutcome::Failed(err),
+        };
+        outcomes.push((file_path, outcome));
     }
+    outcomes
 }
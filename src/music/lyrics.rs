@@ -0,0 +1,408 @@
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+/// A lyrics line along with its display time
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimedLine {
+    /// Display time in milliseconds from track start
+    pub time_ms: u32,
+    pub text: String,
+}
+
+/// Timed lines of LRC formatted lyrics, sorted by display time. Lines
+/// without time tag and ID tags (e.g. `[ar:Artist]`) are skipped
+pub fn parse_lrc(text: &str) -> Vec<TimedLine> {
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let mut rest = line.trim();
+        let mut times = Vec::new();
+        while let Some((tag, tail)) = rest.strip_prefix('[').and_then(|tag| tag.split_once(']')) {
+            match parse_lrc_time(tag) {
+                Some(time_ms) => times.push(time_ms),
+                None => break,
+            }
+            rest = tail;
+        }
+        for time_ms in times {
+            lines.push(TimedLine {
+                time_ms,
+                text: rest.trim().to_string(),
+            });
+        }
+    }
+    lines.sort_by_key(|line| line.time_ms);
+    lines
+}
+
+/// Milliseconds of a LRC time tag (`mm:ss`, `mm:ss.xx` or `mm:ss.xxx`)
+fn parse_lrc_time(tag: &str) -> Option<u32> {
+    let (minutes, seconds) = tag.split_once(':')?;
+    let minutes: u32 = minutes.trim().parse().ok()?;
+    let (seconds, fraction) = seconds.split_once(['.', ':']).unwrap_or((seconds, "0"));
+    let seconds: u32 = seconds.trim().parse().ok()?;
+    if seconds >= 60 || fraction.is_empty() || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    // fraction digits stand for tenths, hundredths or thousandths
+    let millis = format!("{:0<3}", fraction).get(..3)?.parse::<u32>().ok()?;
+    minutes
+        .checked_mul(60)?
+        .checked_add(seconds)?
+        .checked_mul(1000)?
+        .checked_add(millis)
+}
+
+/// LRC formatted text of timed lines
+pub fn to_lrc(lines: &[TimedLine]) -> String {
+    lines
+        .iter()
+        .map(|line| {
+            format!(
+                "[{:02}:{:02}.{:02}]{}",
+                line.time_ms / 60_000,
+                line.time_ms / 1000 % 60,
+                line.time_ms % 1000 / 10,
+                line.text
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Plain text of timed lines
+pub fn to_plain_text(lines: &[TimedLine]) -> String {
+    lines
+        .iter()
+        .map(|line| line.text.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// `.lrc` file lying next to an audio file, with the same name
+pub fn sidecar_path(file: &Path) -> Option<PathBuf> {
+    ["lrc", "LRC"]
+        .iter()
+        .map(|extension| file.with_extension(extension))
+        .find(|sidecar| sidecar.is_file())
+}
+
+/// Modification time of the `.lrc` file lying next to an audio file (0 if
+/// none)
+pub fn sidecar_modified(file: &Path) -> u64 {
+    sidecar_path(file)
+        .and_then(|sidecar| sidecar.metadata().ok())
+        .map(|metadata| super::metadata::modification_time(&metadata))
+        .unwrap_or(0)
+}
+
+/// Timed lines of the ID3v2 `SYLT` frames of a file, when timestamped in
+/// milliseconds (symphonia skips these frames)
+pub fn read_id3v2_sylt(file: &Path) -> Vec<TimedLine> {
+    let mut lines = Vec::new();
+    let tag = match read_id3v2_tag(file) {
+        Some(tag) => tag,
+        None => return lines,
+    };
+    for (id, body) in Id3v2Frames::new(&tag.frames, tag.major) {
+        if id == "SYLT" || id == "SLT" {
+            lines.extend(parse_sylt(body));
+        }
+    }
+    lines.sort_by_key(|line| line.time_ms);
+    lines
+}
+
+/// ID3v2 tag found at the start of a file
+struct Id3v2Tag {
+    /// Major version (2, 3 or 4)
+    major: u8,
+    /// Frames data, extended header excluded
+    frames: Vec<u8>,
+}
+
+fn read_id3v2_tag(file: &Path) -> Option<Id3v2Tag> {
+    use std::io::Read;
+
+    let mut f = std::fs::File::open(file).ok()?;
+    let mut header = [0u8; 10];
+    f.read_exact(&mut header).ok()?;
+    if &header[..3] != b"ID3" || !(2..=4).contains(&header[3]) {
+        return None;
+    }
+    let (major, flags) = (header[3], header[5]);
+    let mut data = vec![0u8; syncsafe(&header[6..10]) as usize];
+    f.read_exact(&mut data).ok()?;
+    // whole tag unsynchronisation (ID3v2.4 applies it per frame)
+    if flags & 0x80 != 0 && major < 4 {
+        data = resynchronise(&data);
+    }
+    if flags & 0x40 != 0 && major > 2 {
+        let size = match major {
+            3 => u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize + 4,
+            _ => syncsafe(data.get(..4)?) as usize,
+        };
+        data.drain(..size.min(data.len()));
+    }
+    Some(Id3v2Tag { major, frames: data })
+}
+
+/// Frames (identifier and body) of ID3v2 tag data
+struct Id3v2Frames<'a> {
+    data: &'a [u8],
+    major: u8,
+}
+
+impl<'a> Id3v2Frames<'a> {
+    fn new(data: &'a [u8], major: u8) -> Self {
+        Self { data, major }
+    }
+}
+
+impl<'a> Iterator for Id3v2Frames<'a> {
+    type Item = (String, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (id_len, header_len) = if self.major == 2 { (3, 6) } else { (4, 10) };
+        let header = self.data.get(..header_len)?;
+        // padding reached
+        if header[0] == 0 {
+            return None;
+        }
+        let id = String::from_utf8_lossy(&header[..id_len]).into_owned();
+        let size = match self.major {
+            2 => u32::from_be_bytes([0, header[3], header[4], header[5]]),
+            3 => u32::from_be_bytes(header[4..8].try_into().ok()?),
+            _ => syncsafe(&header[4..8]),
+        } as usize;
+        let body = self.data.get(header_len..header_len + size)?;
+        self.data = &self.data[header_len + size..];
+
+        let mut body = body.to_vec();
+        if self.major == 4 {
+            let flags = header[9];
+            // compressed or encrypted
+            if flags & 0x0c != 0 {
+                return Some((id, Vec::new()));
+            }
+            if flags & 0x02 != 0 {
+                body = resynchronise(&body);
+            }
+            // data length indicator
+            if flags & 0x01 != 0 {
+                body.drain(..4.min(body.len()));
+            }
+        } else if self.major == 3 && header[9] & 0xc0 != 0 {
+            return Some((id, Vec::new()));
+        }
+        Some((id, body))
+    }
+}
+
+/// Timed lines of a `SYLT` frame body
+fn parse_sylt(body: Vec<u8>) -> Vec<TimedLine> {
+    let mut lines = Vec::new();
+    // encoding, language (3), timestamp format and content type
+    if body.len() < 6 || body[4] != 2 {
+        return lines;
+    }
+    let encoding = body[0];
+    let mut rest = &body[6..];
+    // content descriptor
+    if let Some((_, tail)) = read_terminated_text(rest, encoding) {
+        rest = tail;
+    }
+    while let Some((text, tail)) = read_terminated_text(rest, encoding) {
+        let time = match tail.get(..4) {
+            Some(time) => u32::from_be_bytes([time[0], time[1], time[2], time[3]]),
+            None => break,
+        };
+        lines.push(TimedLine {
+            time_ms: time,
+            text: text.trim_matches(['\n', '\r']).to_string(),
+        });
+        rest = &tail[4..];
+    }
+    lines
+}
+
+/// Text up to its encoding terminator, along with the data remaining past it
+fn read_terminated_text(data: &[u8], encoding: u8) -> Option<(String, &[u8])> {
+    if data.is_empty() {
+        return None;
+    }
+    let wide = encoding == 1 || encoding == 2;
+    let end = if wide {
+        data.chunks(2).position(|unit| unit == [0, 0]).map(|units| units * 2)
+    } else {
+        data.iter().position(|&b| b == 0)
+    };
+    let (text, rest) = match end {
+        Some(end) => (&data[..end], &data[(end + if wide { 2 } else { 1 }).min(data.len())..]),
+        None => (data, &data[data.len()..]),
+    };
+    let text = match encoding {
+        0 => text.iter().map(|&b| b as char).collect(),
+        1 | 2 => {
+            let (mut big_endian, mut text) = (encoding == 2, text);
+            if text.starts_with(&[0xfe, 0xff]) || text.starts_with(&[0xff, 0xfe]) {
+                big_endian = text[0] == 0xfe;
+                text = &text[2..];
+            }
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|unit| {
+                    if big_endian {
+                        u16::from_be_bytes([unit[0], unit[1]])
+                    } else {
+                        u16::from_le_bytes([unit[0], unit[1]])
+                    }
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).into_owned(),
+    };
+    Some((text, rest))
+}
+
+/// Value of a 28 bits syncsafe integer
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |value, &b| (value << 7) | u32::from(b & 0x7f))
+}
+
+/// Data with unsynchronisation (`0xff 0x00` sequences) reverted
+fn resynchronise(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (index, &b) in data.iter().enumerate() {
+        if b == 0 && index > 0 && data[index - 1] == 0xff {
+            continue;
+        }
+        out.push(b);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(time_ms: u32, text: &str) -> TimedLine {
+        TimedLine {
+            time_ms,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn lrc_time_tags() {
+        assert_eq!(parse_lrc_time("01:02"), Some(62_000));
+        assert_eq!(parse_lrc_time("01:02.5"), Some(62_500));
+        assert_eq!(parse_lrc_time("01:02.34"), Some(62_340));
+        assert_eq!(parse_lrc_time("01:02.345"), Some(62_345));
+        assert_eq!(parse_lrc_time("01:02:34"), Some(62_340));
+        assert_eq!(parse_lrc_time("01:60.00"), None);
+        assert_eq!(parse_lrc_time("01:02."), None);
+        assert_eq!(parse_lrc_time("ar:Artist"), None);
+        assert_eq!(parse_lrc_time("71583:00.00"), None);
+        assert_eq!(parse_lrc_time("4294967295:00"), None);
+    }
+
+    #[test]
+    fn lrc_lines_are_sorted_and_id_tags_skipped() {
+        let text = "[ar:Artist]\n[00:12.00]Second\n[00:01.50][00:20.00] Repeated \nuntimed line\n";
+        assert_eq!(parse_lrc("[71583:00.00]Overflowing"), Vec::new());
+        assert_eq!(
+            parse_lrc(text),
+            vec![
+                line(1_500, "Repeated"),
+                line(12_000, "Second"),
+                line(20_000, "Repeated")
+            ]
+        );
+    }
+
+    #[test]
+    fn lrc_round_trip() {
+        let lines = vec![line(1_500, "First"), line(62_340, "Second")];
+        let text = to_lrc(&lines);
+        assert_eq!(text, "[00:01.50]First\n[01:02.34]Second");
+        assert_eq!(parse_lrc(&text), lines);
+        assert_eq!(to_plain_text(&lines), "First\nSecond");
+    }
+
+    #[test]
+    fn sylt_latin1_frame() {
+        // latin-1, "eng", milliseconds, lyrics, empty descriptor
+        let mut body = vec![0, b'e', b'n', b'g', 2, 1, 0];
+        body.extend(b"First\0");
+        body.extend(1_500u32.to_be_bytes());
+        body.extend(b"\nSecond\0");
+        body.extend(3_000u32.to_be_bytes());
+        assert_eq!(parse_sylt(body), vec![line(1_500, "First"), line(3_000, "Second")]);
+    }
+
+    #[test]
+    fn sylt_utf16_frame() {
+        let mut body = vec![1, b'e', b'n', b'g', 2, 1, 0xff, 0xfe, 0, 0];
+        body.extend([0xff, 0xfe, b'H', 0, b'i', 0, 0, 0]);
+        body.extend(250u32.to_be_bytes());
+        assert_eq!(parse_sylt(body), vec![line(250, "Hi")]);
+    }
+
+    #[test]
+    fn sylt_frame_timed_in_mpeg_frames_is_skipped() {
+        let mut body = vec![0, b'e', b'n', b'g', 1, 1, 0];
+        body.extend(b"First\0");
+        body.extend(10u32.to_be_bytes());
+        assert!(parse_sylt(body).is_empty());
+    }
+
+    #[test]
+    fn sylt_truncated_timestamp_stops_parsing() {
+        let mut body = vec![0, b'e', b'n', b'g', 2, 1, 0];
+        body.extend(b"First\0");
+        body.extend(1_500u32.to_be_bytes());
+        body.extend(b"Second\0\0\0");
+        assert_eq!(parse_sylt(body), vec![line(1_500, "First")]);
+    }
+
+    #[test]
+    fn terminated_text() {
+        assert_eq!(read_terminated_text(b"ab\0cd", 3), Some(("ab".to_string(), &b"cd"[..])));
+        assert_eq!(read_terminated_text(b"ab", 0), Some(("ab".to_string(), &b""[..])));
+        assert_eq!(
+            read_terminated_text(&[0, b'a', 0, 0, 1], 2),
+            Some(("a".to_string(), &[1u8][..]))
+        );
+        assert_eq!(read_terminated_text(b"", 0), None);
+    }
+
+    #[test]
+    fn syncsafe_integers() {
+        assert_eq!(syncsafe(&[0, 0, 0x02, 0x01]), 257);
+        assert_eq!(syncsafe(&[0x7f, 0x7f, 0x7f, 0x7f]), 0x0fff_ffff);
+    }
+
+    #[test]
+    fn unsynchronisation_is_reverted() {
+        assert_eq!(
+            resynchronise(&[0xff, 0x00, 0xe0, 0x00, 0xff]),
+            vec![0xff, 0xe0, 0x00, 0xff]
+        );
+    }
+
+    #[test]
+    fn id3v2_frames() {
+        let mut data = b"TIT2".to_vec();
+        data.extend([0, 0, 0, 3, 0, 0]);
+        data.extend(b"\0Hi");
+        data.extend(b"SYLT");
+        data.extend([0, 0, 0, 1, 0, 0, 7]);
+        data.extend([0; 4]);
+        let frames: Vec<(String, Vec<u8>)> = Id3v2Frames::new(&data, 3).collect();
+        assert_eq!(
+            frames,
+            vec![("TIT2".to_string(), b"\0Hi".to_vec()), ("SYLT".to_string(), vec![7])]
+        );
+    }
+}
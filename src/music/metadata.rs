@@ -1,22 +1,61 @@
-use base64;
-use fastrand;
 use log::{debug, warn};
 use new_mime_guess;
 use phf::phf_map;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use symphonia::core::codecs::CodecParameters;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatReader;
 use symphonia::core::io::MediaSourceStream;
+use symphonia::core::units::TimeBase;
+use unicode_normalization::UnicodeNormalization;
 
+use super::cue::{self, CueSheet};
+use super::lyrics;
+use super::picture::{self, Picture};
+use super::quality::QualityPolicy;
+use super::template::PathTemplate;
 use super::track::Track;
 use crate::CanariaError;
 
 const DEBUG_MUSIC_METADATA: bool = true;
-const UNINITIALIZED_STR: &str = "_nihil_";
+pub(crate) const UNINITIALIZED_STR: &str = "_nihil_";
+/// Prefix of references derived from audio content for uncatalogued tracks
+pub(crate) const LOCAL_REF_PREFIX: &str = "LOCAL:";
+
+/// Metadata extraction parameters
+#[derive(Clone, Debug)]
+pub struct MetadataOptions {
+    /// Separators splitting genre and tag values holding many of them
+    pub tag_separators: Vec<String>,
+
+    /// Directory where embedded pictures are stored, named by content hash
+    pub image_cache: PathBuf,
+
+    /// File name patterns of folder images used as artwork, by decreasing
+    /// preference
+    pub cover_patterns: Vec<String>,
+
+    /// Path layouts providing the fields missing from tags, by decreasing
+    /// preference
+    pub path_templates: Vec<PathTemplate>,
+
+    /// Which files get catalogued, and which issues get reported
+    pub quality: QualityPolicy,
+
+    /// Track fields of tags by raw key (lowercase, e.g. `txxx:catalognumber`),
+    /// prevailing over default mappings
+    pub tag_mapping: HashMap<String, String>,
+}
 
 impl Track {
     fn new(filepath: &Path) -> Self {
-        let file_path = filepath.canonicalize().unwrap().into();
-        let file_size = filepath.metadata().unwrap().len() / 1024;
+        let file_path = filepath.canonicalize().unwrap();
+        let file_metadata = filepath.metadata().unwrap();
+        let file_size = file_metadata.len() / 1024;
+        let file_modified = modification_time(&file_metadata);
         let extension = filepath
             .extension()
             .unwrap_or(OsStr::new(UNINITIALIZED_STR))
@@ -28,59 +67,536 @@ impl Track {
         Self {
             title: UNINITIALIZED_STR.into(),
             artist: UNINITIALIZED_STR.into(),
+            artists: Vec::new(),
+            artists_sort: Vec::new(),
             artist_ref: Vec::new(),
             original_year: None,
+            release_date: None,
+            original_date: None,
             album: None,
             album_ref: None,
+            catalog_number: None,
+            album_artist: None,
+            album_artist_sort: None,
+            album_artist_ref: Vec::new(),
+            compilation: false,
+            track_number: None,
+            track_total: None,
+            disc_number: None,
+            disc_total: None,
+            genres: Vec::new(),
             tags: Vec::new(),
+            lyrics: None,
+            synced_lyrics: None,
             track_ref: UNINITIALIZED_STR.into(),
             duration_seconds: None,
+            inferred: Vec::new(),
+            quality_issues: Vec::new(),
+            needs_tagging: false,
+            cue_sheet: None,
+            span: None,
+            content_hash: None,
             file_path,
             file_size,
+            file_modified,
             extension,
             mime_type,
-            picture_mime_type: None,
-            picture: None,
+            codec: None,
+            sample_rate: None,
+            bits_per_sample: None,
+            channels: None,
+            channel_layout: None,
+            bitrate: None,
+            pictures: Vec::new(),
+            cover: None,
+            cover_modified: 0,
+            cue_modified: 0,
+            lyrics_modified: 0,
         }
     }
+    /// Records stream technical properties from its codec parameters
+    fn set_audio_properties(&mut self, params: &CodecParameters) {
+        self.codec = symphonia::default::get_codecs()
+            .get_codec(params.codec)
+            .map(|descriptor| descriptor.short_name.to_string());
+        self.sample_rate = params.sample_rate;
+        self.bits_per_sample = params.bits_per_sample;
+        self.channels = params.channels.map(|channels| channels.count() as u32);
+        self.channel_layout = match (params.channel_layout, params.channels) {
+            (Some(layout), _) => Some(format!("{:?}", layout)),
+            (None, Some(channels)) if channels.count() == 1 => Some("Mono".into()),
+            (None, Some(channels)) if channels.count() == 2 => Some("Stereo".into()),
+            (None, Some(channels)) => Some(format!("{:?}", channels)),
+            (None, None) => None,
+        };
+    }
     fn set_field(&mut self, field: &str, value: String) {
         match field {
             "title" => self.title = value,
-            "artist" => self.artist = value,
-            "artist_id" | "artist_ref" => self.artist_ref.push(value),
-            "original_year" => self.original_year = value.parse().ok(),
+            "artist" if self.artist == UNINITIALIZED_STR => self.artist = value,
+            "artist" => self.artist = format!("{}; {}", self.artist, value),
+            "artists" => self.artists.push(value),
+            "artist_sort" => self.artists_sort.push(value),
+            "artist_id" | "artist_ref" => self.artist_ref.extend(split_references(&value)),
+            "date" => self.release_date = most_precise_date(self.release_date.take(), &value),
+            "original_date" => self.original_date = most_precise_date(self.original_date.take(), &value),
             "album" => self.album = Some(value),
             "album_id" | "album_ref" => self.album_ref = Some(value),
-            "tags" => self.tags.push(value),
+            "album_artist" => {
+                self.album_artist = match self.album_artist.take() {
+                    Some(album_artist) => Some(format!("{}; {}", album_artist, value)),
+                    None => Some(value),
+                }
+            }
+            "album_artist_sort" => self.album_artist_sort = Some(value),
+            "album_artist_id" => self.album_artist_ref.extend(split_references(&value)),
+            "compilation" => {
+                self.compilation = matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes")
+            }
+            "track_number" => {
+                let (number, total) = parse_position(&value);
+                self.track_number = number;
+                self.track_total = self.track_total.or(total);
+            }
+            "track_total" => self.track_total = parse_position(&value).0,
+            "disc_number" => {
+                let (number, total) = parse_position(&value);
+                self.disc_number = number;
+                self.disc_total = self.disc_total.or(total);
+            }
+            "disc_total" => self.disc_total = parse_position(&value).0,
+            "genre" => self.genres.push(value),
+            "tags" | "mood" => self.tags.push(value),
+            "lyrics" => self.set_lyrics(&value),
+            "cue_sheet" => self.cue_sheet = Some(CueSheet::parse(&value)),
+            "catalog_number" => self.catalog_number = Some(value),
             "track_id" => self.track_ref = value,
-            "picture" => self.picture = Some(value),
             _ => warn!("trying to set unexpected metadata field `{}`", field),
         }
     }
+
+    /// Records lyrics, as synchronized ones too when LRC formatted
+    fn set_lyrics(&mut self, value: &str) {
+        let lines = lyrics::parse_lrc(value);
+        if lines.is_empty() {
+            self.lyrics = Some(value.trim().to_string());
+        } else {
+            self.lyrics = Some(lyrics::to_plain_text(&lines));
+            self.synced_lyrics = Some(lyrics::to_lrc(&lines));
+        }
+    }
+
+    /// Looks for a CUE sheet next to the file when none is embedded, and
+    /// completes file tags with the sheet ones (e.g. untagged image rips)
+    fn apply_cue_sheet(&mut self, file: &Path) {
+        self.cue_modified = file.parent().map(cue::sidecars_modified).unwrap_or(0);
+        if self.cue_sheet.is_none() {
+            self.cue_sheet = cue::find_sidecar(file).map(|text| CueSheet::parse(&text));
+        }
+        let sheet = match self.cue_sheet.take() {
+            Some(sheet) => sheet,
+            None => return,
+        };
+        let (title, performer) = match sheet.tracks_of(file).as_slice() {
+            [] => return,
+            // a file of a sheet laying out one file per track
+            [track] => {
+                self.track_number.get_or_insert(track.number);
+                (track.title.clone(), track.performer.clone().or_else(|| sheet.performer.clone()))
+            }
+            _ => (sheet.title.clone(), sheet.performer.clone()),
+        };
+        if let Some(title) = title {
+            if self.title == UNINITIALIZED_STR {
+                self.title = title;
+            }
+        }
+        if let Some(album) = &sheet.title {
+            self.album.get_or_insert_with(|| album.clone());
+        }
+        if let Some(performer) = performer {
+            if self.artist == UNINITIALIZED_STR {
+                self.artist = performer;
+            }
+        }
+        if self.genres.is_empty() {
+            self.genres.extend(sheet.genre.clone());
+        }
+        if self.release_date.is_none() {
+            self.release_date = sheet.date.as_deref().and_then(parse_date);
+        }
+        self.cue_sheet = Some(sheet);
+    }
+
+    /// Fills fields missing from tags with the values captured by the first
+    /// path template matching the file, marking them as inferred
+    fn apply_path_templates(&mut self, file: &Path, templates: &[PathTemplate]) {
+        let (template, captured) = match templates
+            .iter()
+            .find_map(|template| template.capture(file).map(|captured| (template, captured)))
+        {
+            Some(matched) => matched,
+            None => return,
+        };
+        debug!("{} matches path template `{}`", file.display(), template);
+        for (field, value) in captured {
+            let (md_field, missing) = match field {
+                "artist" => ("artist", self.artist == UNINITIALIZED_STR || self.artist.is_empty()),
+                "albumartist" => ("album_artist", self.album_artist.is_none()),
+                "album" => ("album", self.album.is_none()),
+                "title" => ("title", self.title == UNINITIALIZED_STR || self.title.is_empty()),
+                "track" => ("track_number", self.track_number.is_none()),
+                "disc" => ("disc_number", self.disc_number.is_none()),
+                "year" => ("date", self.release_date.is_none()),
+                "genre" => ("genre", self.genres.is_empty()),
+                _ => continue,
+            };
+            if missing {
+                if md_field == "artist" {
+                    self.artist = UNINITIALIZED_STR.into();
+                }
+                self.set_field(md_field, value);
+                self.inferred.push(field.to_string());
+            }
+        }
+        // tracks of layouts naming album artists only are credited to them
+        if self.artist == UNINITIALIZED_STR || self.artist.is_empty() {
+            if let Some(album_artist) = &self.album_artist {
+                self.artist = album_artist.clone();
+                self.inferred.push("artist".into());
+            }
+        }
+    }
+
+    /// Looks for a folder image, used as artwork when no front cover is
+    /// embedded
+    fn find_cover(&mut self, file: &Path, options: &MetadataOptions) {
+        let sidecar = file
+            .parent()
+            .and_then(|dir| picture::find_sidecar(dir, &options.cover_patterns));
+        let (path, modified) = match sidecar {
+            Some(sidecar) => sidecar,
+            None => return,
+        };
+        self.cover_modified = modified;
+        if self.has_front_cover() {
+            return;
+        }
+        let mime_type = new_mime_guess::from_path(&path).first_or_octet_stream().to_string();
+        let cached = std::fs::read(&path)
+            .and_then(|data| Picture::cache(&data, &mime_type, &options.image_cache));
+        match cached {
+            Ok(mut cover) => {
+                cover.usage = Some(StandardVisualKey::FrontCover);
+                self.cover = Some(cover);
+            }
+            Err(err) => warn!("{}: folder image not cached: {}", path.display(), err),
+        }
+    }
+
+    /// Completes embedded lyrics with ID3v2 `SYLT` frames and `.lrc` sidecar
+    fn gather_lyrics(&mut self, file: &Path) {
+        self.lyrics_modified = lyrics::sidecar_modified(file);
+        if self.synced_lyrics.is_none() {
+            let lines = lyrics::read_id3v2_sylt(file);
+            if !lines.is_empty() {
+                self.synced_lyrics = Some(lyrics::to_lrc(&lines));
+                self.lyrics.get_or_insert_with(|| lyrics::to_plain_text(&lines));
+            }
+        }
+        if self.lyrics.is_some() && self.synced_lyrics.is_some() {
+            return;
+        }
+        let sidecar = lyrics::sidecar_path(file).and_then(|sidecar| std::fs::read(sidecar).ok());
+        if let Some(sidecar) = sidecar {
+            let (lyrics, synced_lyrics) = (self.lyrics.take(), self.synced_lyrics.take());
+            self.set_lyrics(&String::from_utf8_lossy(&sidecar));
+            self.lyrics = lyrics.or(self.lyrics.take());
+            self.synced_lyrics = synced_lyrics.or(self.synced_lyrics.take());
+        }
+    }
+}
+
+/// Normalized form of a name, identifying it regardless of case, spacing and
+/// Unicode representation
+pub fn name_key(name: &str) -> String {
+    name.nfkc()
+        .collect::<String>()
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Individual references of a reference value, as ID3v2.3 joins multiple
+/// IDs in a single frame
+fn split_references(value: &str) -> Vec<String> {
+    value
+        .split(['/', ';'])
+        .map(|reference| reference.trim().to_string())
+        .filter(|reference| !reference.is_empty())
+        .collect()
+}
+
+/// ISO 8601 calendar date (`YYYY`, `YYYY-MM` or `YYYY-MM-DD`) of a date
+/// value, ignoring any time part (e.g. ID3v2.4 timestamps)
+fn parse_date(value: &str) -> Option<String> {
+    let date = value.trim().split(['T', ' ']).next()?;
+    let mut parts = date.split(['-', '/', '.']);
+    let year = parts.next().filter(|year| year.len() == 4)?.parse::<u16>().ok()?;
+    let month = parts
+        .next()
+        .and_then(|month| month.parse::<u8>().ok())
+        .filter(|month| (1..=12).contains(month));
+    // impossible days (e.g. `2009-02-30`) are dropped, as Dgraph refuses them
+    let day = parts
+        .next()
+        .and_then(|day| day.parse::<u8>().ok())
+        .filter(|day| month.is_some_and(|month| (1..=days_in_month(year, month)).contains(day)));
+    Some(match (month, day) {
+        (Some(month), Some(day)) => format!("{:04}-{:02}-{:02}", year, month, day),
+        (Some(month), None) => format!("{:04}-{:02}", year, month),
+        (None, _) => format!("{:04}", year),
+    })
+}
+
+/// Number of days of a month in the Gregorian calendar
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// The most precise of a known date and a newly tagged date value
+fn most_precise_date(known: Option<String>, value: &str) -> Option<String> {
+    match (known, parse_date(value)) {
+        (Some(known), Some(date)) if date.len() > known.len() => Some(date),
+        (Some(known), _) => Some(known),
+        (None, date) => date,
+    }
+}
+
+/// Year of an ISO 8601 date
+fn date_year(date: &str) -> Option<u16> {
+    date.get(..4)?.parse().ok()
+}
+
+/// Position along with the optional total count, as tagged in `3` or `3/12`
+/// forms (missing or zero values are ignored)
+fn parse_position(value: &str) -> (Option<u32>, Option<u32>) {
+    let mut parts = value
+        .splitn(2, '/')
+        .map(|part| part.trim().parse::<u32>().ok().filter(|&n| n > 0));
+    (parts.next().flatten(), parts.next().flatten())
+}
+
+/// Individual names of many tag values, split on `separators`, with spacing
+/// normalized and duplicates removed
+fn split_names(values: &[String], separators: &[String]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for value in values {
+        let mut parts = vec![value.clone()];
+        for separator in separators.iter().filter(|separator| !separator.is_empty()) {
+            parts = parts
+                .iter()
+                .flat_map(|part| part.split(separator.as_str()))
+                .map(String::from)
+                .collect();
+        }
+        for part in parts {
+            let name = part.split_whitespace().collect::<Vec<&str>>().join(" ");
+            if !name.is_empty() && !names.iter().any(|known| name_key(known) == name_key(&name)) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// Genre names of a genre value, resolving ID3v1 genre codes whether bare
+/// (ID3v2.4, e.g. `17`) or parenthesized ahead of a refinement (ID3v2.3, e.g.
+/// `(17)(31)` or `(17)Rock`)
+fn genre_names(value: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = value.trim();
+    while rest.starts_with('(') && !rest.starts_with("((") {
+        match rest[1..].split_once(')') {
+            Some((code, tail)) => {
+                names.extend(id3v1_genre(code));
+                rest = tail.trim_start();
+            }
+            None => break,
+        }
+    }
+    // a refinement starting with a parenthesis has it doubled
+    if rest.starts_with("((") {
+        rest = &rest[1..];
+    }
+    if !rest.is_empty() {
+        names.push(id3v1_genre(rest).unwrap_or_else(|| rest.to_string()));
+    }
+    names
+}
+
+/// ID3v1 genres (Winamp extensions included) by code, #133 left out as
+/// offensive
+const ID3V1_GENRES: [&str; 192] = [
+    "Blues", "Classic rock", "Country", "Dance", "Disco", "Funk", "Grunge", "Hip-Hop",
+    "Jazz", "Metal", "New Age", "Oldies", "Other", "Pop", "Rhythm and Blues", "Rap",
+    "Reggae", "Rock", "Techno", "Industrial", "Alternative", "Ska", "Death metal",
+    "Pranks", "Soundtrack", "Euro-Techno", "Ambient", "Trip-Hop", "Vocal", "Jazz & Funk",
+    "Fusion", "Trance", "Classical", "Instrumental", "Acid", "House", "Game",
+    "Sound clip", "Gospel", "Noise", "Alternative Rock", "Bass", "Soul", "Punk", "Space",
+    "Meditative", "Instrumental Pop", "Instrumental Rock", "Ethnic", "Gothic", "Darkwave",
+    "Techno-Industrial", "Electronic", "Pop-Folk", "Eurodance", "Dream", "Southern Rock",
+    "Comedy", "Cult", "Gangsta", "Top 40", "Christian Rap", "Pop/Funk", "Jungle",
+    "Native US", "Cabaret", "New Wave", "Psychedelic", "Rave", "Show tunes", "Trailer",
+    "Lo-Fi", "Tribal", "Acid Punk", "Acid Jazz", "Polka", "Retro", "Musical",
+    "Rock 'n Roll", "Hard Rock", "Folk", "Folk-Rock", "National Folk", "Swing",
+    "Fast Fusion", "Bebop", "Latin", "Revival", "Celtic", "Bluegrass", "Avantgarde",
+    "Gothic Rock", "Progressive Rock", "Psychedelic Rock", "Symphonic Rock", "Slow rock",
+    "Big Band", "Chorus", "Easy Listening", "Acoustic", "Humour", "Speech", "Chanson",
+    "Opera", "Chamber music", "Symphonia", "Symphony", "Booty bass", "Primus",
+    "Porn groove", "Satire", "Slow jam", "Club", "Tango", "Samba", "Folklore", "Ballad",
+    "Power ballad", "Rhythmic Soul", "Freestyle", "Duet", "Punk Rock", "Drum solo",
+    "A cappella", "Euro-House", "Dance Hall", "Goa", "Drum & Bass", "Club-House",
+    "Hardcore Techno", "Terror", "Indie", "BritPop", "", "Polsk Punk", "Beat",
+    "Christian Gangsta Rap", "Heavy Metal", "Black Metal", "Crossover",
+    "Contemporary Christian", "Christian rock", "Merengue", "Salsa", "Thrash Metal",
+    "Anime", "Jpop", "Synthpop", "Abstract", "Art Rock", "Baroque", "Bhangra", "Big beat",
+    "Breakbeat", "Chillout", "Downtempo", "Dub", "EBM", "Eclectic", "Electro",
+    "Electroclash", "Emo", "Experimental", "Garage", "Global", "IDM", "Illbient",
+    "Industro-Goth", "Jam Band", "Krautrock", "Leftfield", "Lounge", "Math Rock",
+    "New Romantic", "Nu-Breakz", "Post-Punk", "Post-Rock", "Psytrance", "Shoegaze",
+    "Space Rock", "Trop Rock", "World Music", "Neoclassical", "Audiobook",
+    "Audio theatre", "Neue Deutsche Welle", "Podcast", "Indie-Rock", "G-Funk", "Dubstep",
+    "Garage Rock", "Psybient",
+];
+
+/// Genre name of an ID3v1 genre code
+fn id3v1_genre(code: &str) -> Option<String> {
+    match code.trim() {
+        "RX" => Some("Remix".into()),
+        "CR" => Some("Cover".into()),
+        code => code
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| ID3V1_GENRES.get(index))
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_string()),
+    }
+}
+
+/// File modification time in seconds since UNIX epoch (0 when unavailable)
+pub fn modification_time(metadata: &std::fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Track field of tags standardized by symphonia, whatever their format
+fn standard_field(key: StandardTagKey) -> Option<&'static str> {
+    Some(match key {
+        StandardTagKey::TrackTitle => "title",
+        StandardTagKey::Artist => "artist",
+        StandardTagKey::SortArtist => "artist_sort",
+        StandardTagKey::Album => "album",
+        StandardTagKey::AlbumArtist => "album_artist",
+        StandardTagKey::SortAlbumArtist => "album_artist_sort",
+        StandardTagKey::Compilation => "compilation",
+        StandardTagKey::Genre => "genre",
+        StandardTagKey::Lyrics => "lyrics",
+        StandardTagKey::Mood => "mood",
+        StandardTagKey::TrackNumber => "track_number",
+        StandardTagKey::TrackTotal => "track_total",
+        StandardTagKey::DiscNumber => "disc_number",
+        StandardTagKey::DiscTotal => "disc_total",
+        StandardTagKey::Date | StandardTagKey::ReleaseDate => "date",
+        StandardTagKey::OriginalDate => "original_date",
+        StandardTagKey::IdentCatalogNumber => "catalog_number",
+        StandardTagKey::MusicBrainzAlbumId => "album_id",
+        StandardTagKey::MusicBrainzArtistId => "artist_id",
+        StandardTagKey::MusicBrainzAlbumArtistId => "album_artist_id",
+        StandardTagKey::MusicBrainzReleaseTrackId => "track_id",
+        _ => return None,
+    })
 }
 
+/// Tags symphonia does not standardize, by raw key
 static TAG_X_MAP: phf::Map<&'static str, &'static str> = phf_map! {
     // From ID3v2 cases
-    "APIC" => "picture",
-    "TIT2" => "title",
-    "TPE1" => "artist",
-    "TALB" => "album",
-    "TORY" => "original_year",
+    "TXXX:ARTISTS" => "artists",
+    "TCMP" => "compilation",
+    "TXXX:MOOD" => "mood",
     "TXXX:MusicBrainz Album Id" => "album_id",
     "TXXX:MusicBrainz Artist Id" => "artist_id",
+    "TXXX:MusicBrainz Album Artist Id" => "album_artist_id",
     "TXXX:MusicBrainz Release Track Id" => "track_id",
     // From VorbisComment cases
-    "TITLE" => "title",
-    "ARTIST" => "artist",
-    "ALBUM" => "album",
-    "ORIGINALYEAR" => "original_year",
-    "METADATA_BLOCK_PICTURE" => "picture",
-    "MUSICBRAINZ_ALBUMID" => "album_id",
-    "MUSICBRAINZ_ARTISTID" => "artist_id",
-    "MUSICBRAINZ_RELEASETRACKID" => "track_id",
+    "ARTISTS" => "artists",
+    "COMPILATION" => "compilation",
+    "UNSYNCEDLYRICS" => "lyrics",
+    "STYLE" => "tags",
+    "CUESHEET" => "cue_sheet",
+    "ORIGINALYEAR" => "original_date",
 };
 
-pub fn extract_metadata(file: &Path) -> Result<Track, CanariaError> {
+/// Raw keys of tags standardized as dates, yet holding no year (ID3v2.3
+/// `TDAT` day and month, `TIME` and `TRDA` free text)
+const PARTIAL_DATE_KEYS: [&str; 3] = ["TDAT", "TIME", "TRDA"];
+
+/// Fields tags may be mapped to from configuration, `ignore` dropping them
+pub const TAG_FIELDS: [&str; 25] = [
+    "title",
+    "artist",
+    "artists",
+    "artist_sort",
+    "artist_id",
+    "album",
+    "album_id",
+    "album_artist",
+    "album_artist_sort",
+    "album_artist_id",
+    "compilation",
+    "track_number",
+    "track_total",
+    "disc_number",
+    "disc_total",
+    "date",
+    "original_date",
+    "genre",
+    "tags",
+    "mood",
+    "lyrics",
+    "cue_sheet",
+    "catalog_number",
+    "track_id",
+    "ignore",
+];
+
+/// Track field a tag is mapped to: as configured for its raw key, else as
+/// standardized by symphonia, else as known for its raw key
+fn tag_field<'a>(tag: &Tag, mapping: &'a HashMap<String, String>) -> Option<&'a str> {
+    // ID3v2 `COMM` and `USLT` keys are suffixed with their language
+    let key = tag.key.split('!').next().unwrap_or_default();
+    if let Some(field) = mapping.get(&key.to_lowercase()) {
+        return Some(field.as_str()).filter(|field| *field != "ignore");
+    }
+    if PARTIAL_DATE_KEYS.contains(&key) {
+        return None;
+    }
+    tag.std_key
+        .and_then(standard_field)
+        .or_else(|| TAG_X_MAP.get(key).copied())
+}
+
+pub fn extract_metadata(file: &Path, options: &MetadataOptions) -> Result<Track, CanariaError> {
     let file_ext = file.extension();
     if file_ext.is_none() {
         return Err("audio files must have name extension".into());
@@ -100,24 +616,112 @@ pub fn extract_metadata(file: &Path) -> Result<Track, CanariaError> {
     ) {
         Ok(mut probed) => {
             // TODO: Unify first 2 as soon as if let chains get implemented
-            if let Some(metadata_rev) = probed.format.metadata().current() {
-                // TODO: define audio duration
-                extract_tags(metadata_rev, file)
+            let mut track = if let Some(metadata_rev) = probed.format.metadata().current() {
+                extract_tags(metadata_rev, file, options)
             } else if let Some(metadata_rev) =
                 probed.metadata.get().as_ref().and_then(|m| m.current())
             {
-                extract_tags(metadata_rev, file)
+                extract_tags(metadata_rev, file, options)
             } else {
-                return Err("symphonia probed no metadata".into());
-            }
+                // untagged files may still be described by a CUE sheet or their path
+                Ok(Track::new(file))
+            }?;
+            track.apply_cue_sheet(file);
+            track.apply_path_templates(file, &options.path_templates);
+            let (track_id, params) = match probed.format.default_track() {
+                Some(default_track) => (default_track.id, default_track.codec_params.clone()),
+                None => return Err("symphonia probed no audio track".into()),
+            };
+            track.set_audio_properties(&params);
+            let scan = scan_stream(probed.format.as_mut(), track_id);
+            track.duration_seconds = playback_duration(&params, scan.end_ts);
+            track.bitrate = track
+                .duration_seconds
+                .filter(|&seconds| seconds > 0)
+                .map(|seconds| (scan.size * 8 / 1000) as u32 / seconds);
+            track.content_hash = scan.hash;
+            track.gather_lyrics(file);
+            track.find_cover(file, options);
+            track.original_year = track
+                .original_date
+                .as_deref()
+                .or(track.release_date.as_deref())
+                .and_then(date_year);
+            let genres: Vec<String> = split_names(&track.genres, &options.tag_separators)
+                .iter()
+                .flat_map(|genre| genre_names(genre))
+                .collect();
+            track.genres = split_names(&genres, &[]);
+            track.tags = split_names(&track.tags, &options.tag_separators);
+            track
         }
         Err(err) => return Err(format!("metadata extraction fail: {}", err).into()),
-    }?;
+    };
+
+    quality_control(result, &options.quality)
+}
+
+/// Default track audio payload summary, container tags excluded
+struct StreamScan {
+    /// Timestamp right after the last demuxed packet
+    end_ts: u64,
+    /// Payload size in bytes
+    size: u64,
+    /// SHA-256 digest (hex) of the payload, if any
+    hash: Option<String>,
+}
+
+/// Demuxes the whole default track stream, so the audio content can be
+/// identified regardless of tags edition.
+fn scan_stream(format: &mut dyn FormatReader, track_id: u32) -> StreamScan {
+    let mut hasher = Sha256::new();
+    let mut end_ts = 0;
+    let mut size = 0;
+    loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => {
+                end_ts = end_ts.max(packet.ts() + packet.dur());
+                size += packet.data.len() as u64;
+                hasher.update(&packet.data);
+            }
+            Ok(_) => continue,
+            Err(SymphoniaError::IoError(_)) => break,
+            Err(err) => {
+                debug!("stream end not reached: {}", err);
+                break;
+            }
+        }
+    }
+    StreamScan {
+        end_ts,
+        size,
+        hash: if size > 0 {
+            Some(format!("{:x}", hasher.finalize()))
+        } else {
+            None
+        },
+    }
+}
+
+/// Playback duration (in seconds) of a track.
+///
+/// Relies on the frame count declared by the container and, for streams not
+/// declaring it (e.g. VBR MP3 without Xing header), on the timestamp reached
+/// at the end of the stream.
+fn playback_duration(params: &CodecParameters, end_ts: u64) -> Option<u32> {
+    let time_base = params
+        .time_base
+        .or_else(|| params.sample_rate.map(|rate| TimeBase::new(1, rate)))?;
+    let n_frames = params.n_frames.unwrap_or(end_ts);
+    if n_frames == 0 {
+        return None;
+    }
 
-    quality_control(result)
+    let time = time_base.calc_time(n_frames);
+    Some((time.seconds as f64 + time.frac).round() as u32)
 }
 
-use symphonia::core::meta::{MetadataRevision, Size, Tag};
+use symphonia::core::meta::{MetadataRevision, Size, StandardTagKey, StandardVisualKey, Tag};
 
 fn display_tags(tags: &Vec<Tag>) -> String {
     let mut out = "".into();
@@ -127,7 +731,11 @@ fn display_tags(tags: &Vec<Tag>) -> String {
     out
 }
 
-fn extract_tags(md_rev: &MetadataRevision, file: &Path) -> Result<Track, CanariaError> {
+fn extract_tags(
+    md_rev: &MetadataRevision,
+    file: &Path,
+    options: &MetadataOptions,
+) -> Result<Track, CanariaError> {
     let tags = md_rev.tags();
     let mut out = Track::new(file);
 
@@ -155,44 +763,241 @@ fn extract_tags(md_rev: &MetadataRevision, file: &Path) -> Result<Track, Canaria
                 &display_tags(&vis.tags)
             );
         }
-        out.picture_mime_type = Some(vis.media_type.clone());
-        out.picture = Some(base64::encode(&vis.data));
+        match Picture::from_visual(vis, &options.image_cache) {
+            Ok(picture) => out.pictures.push(picture),
+            Err(err) => warn!("{}: picture not cached: {}", file.display(), err),
+        }
     }
 
+    // multi-valued artist tags (e.g. many Vorbis `ARTIST` or a null separated
+    // ID3v2.4 `TPE1`) stand for individual artists when no `ARTISTS` is found
+    let mut artist_values: Vec<String> = Vec::new();
     for tag in tags.iter() {
         if DEBUG_MUSIC_METADATA {
             debug!("probed tag {}: {}", &tag.key, &tag.value);
         }
-        if let Some(md_field) = TAG_X_MAP.get(tag.key.as_str()) {
+        if let Some(md_field) = tag_field(tag, &options.tag_mapping) {
+            if md_field == "artist" {
+                artist_values.push(tag.value.to_string());
+            }
             out.set_field(md_field, tag.value.to_string())
         }
     }
+    if out.artists.is_empty() {
+        out.artists = artist_values;
+    }
     Ok(out)
 }
 
-/// Ensures track meet metadata quality standards
-fn quality_control(track: Track) -> Result<Track, CanariaError> {
-    // fail
-    if track.artist == UNINITIALIZED_STR
-        || track.artist == ""
-        || track.title == UNINITIALIZED_STR
-        || track.title == ""
-    {
-        return Err("poor metadata: artist and/or title".into());
-    }
+/// Ensures track meet metadata quality standards, recording the issues found
+fn quality_control(track: Track, policy: &QualityPolicy) -> Result<Track, CanariaError> {
     let mut track = track;
-    if track.track_ref == UNINITIALIZED_STR || track.track_ref == "" {
-        log::warn!("uncatalogued track");
-        track.track_ref = format!(
-            "TEMPORARY:{}",
-            std::iter::repeat_with(fastrand::alphanumeric)
-                .take(25)
-                .collect::<String>()
-        )
+    let report = policy.check(&track);
+    for issue in &report.issues {
+        if !report.rejections.contains(issue) {
+            log::warn!("{}: {}", track.file_path.display(), issue);
+        }
     }
-    if track.artist_ref.is_empty() {
-        log::warn!("uncatalogued artist")
+    track.quality_issues = report.issues;
+    if !report.rejections.is_empty() {
+        if !policy.record_rejected {
+            return Err(format!("poor metadata: {}", report.rejections.join(", ")).into());
+        }
+        track.needs_tagging = true;
+    }
+    if track.track_ref == UNINITIALIZED_STR || track.track_ref.is_empty() {
+        match &track.content_hash {
+            Some(hash) => track.track_ref = format!("{}{}", LOCAL_REF_PREFIX, hash),
+            None => return Err("uncatalogued track without audio payload".into()),
+        }
     }
 
     Ok(track)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music::track::tests::strings;
+
+    /// Track of an existing file, as tags are applied to probed files only
+    fn probed_track() -> Track {
+        Track::new(Path::new("Cargo.toml"))
+    }
+
+    #[test]
+    fn parse_position_reads_number_and_total() {
+        assert_eq!(parse_position("3"), (Some(3), None));
+        assert_eq!(parse_position("3/12"), (Some(3), Some(12)));
+        assert_eq!(parse_position(" 03 / 12 "), (Some(3), Some(12)));
+    }
+
+    #[test]
+    fn parse_position_ignores_missing_and_zero_values() {
+        assert_eq!(parse_position(""), (None, None));
+        assert_eq!(parse_position("/12"), (None, Some(12)));
+        assert_eq!(parse_position("0/0"), (None, None));
+        assert_eq!(parse_position("A1"), (None, None));
+    }
+
+    #[test]
+    fn position_total_tag_prevails_over_inline_total() {
+        let mut track = probed_track();
+        track.set_field("track_total", "10".into());
+        track.set_field("track_number", "3/12".into());
+        assert_eq!((track.track_number, track.track_total), (Some(3), Some(10)));
+
+        let mut track = probed_track();
+        track.set_field("disc_number", "2/3".into());
+        assert_eq!((track.disc_number, track.disc_total), (Some(2), Some(3)));
+    }
+
+    #[test]
+    fn genre_names_resolve_id3v1_codes() {
+        assert_eq!(genre_names("17"), strings(&["Rock"]));
+        assert_eq!(genre_names("(17)(31)"), strings(&["Rock", "Trance"]));
+        assert_eq!(genre_names("(17)Hard Rock"), strings(&["Rock", "Hard Rock"]));
+        assert_eq!(genre_names("(RX)(CR)"), strings(&["Remix", "Cover"]));
+        assert_eq!(genre_names("(191)"), strings(&["Psybient"]));
+    }
+
+    #[test]
+    fn genre_names_keep_unknown_codes_and_plain_names() {
+        assert_eq!(genre_names("Shoegaze"), strings(&["Shoegaze"]));
+        assert_eq!(genre_names("((Parenthesized)"), strings(&["(Parenthesized)"]));
+        assert_eq!(genre_names("192"), strings(&["192"]));
+        // left out as offensive
+        assert!(genre_names("(133)").is_empty());
+    }
+
+    #[test]
+    fn split_names_split_and_dedup() {
+        let separators = strings(&[";", "/"]);
+        assert_eq!(
+            split_names(&strings(&["Rock; Pop", "rock", "Jazz /  Funk"]), &separators),
+            strings(&["Rock", "Pop", "Jazz", "Funk"])
+        );
+        assert_eq!(split_names(&strings(&["Pop/Funk"]), &[]), strings(&["Pop/Funk"]));
+    }
+
+    #[test]
+    fn parse_date_keeps_calendar_precision() {
+        assert_eq!(parse_date("2009"), Some("2009".into()));
+        assert_eq!(parse_date("2009-3"), Some("2009-03".into()));
+        assert_eq!(parse_date("2009/03/07"), Some("2009-03-07".into()));
+        assert_eq!(parse_date("2009-03-07T20:15:00"), Some("2009-03-07".into()));
+        assert_eq!(parse_date("2009-03-07 20:15"), Some("2009-03-07".into()));
+    }
+
+    #[test]
+    fn parse_date_drops_impossible_parts() {
+        assert_eq!(parse_date("2009-02-30"), Some("2009-02".into()));
+        assert_eq!(parse_date("2011-04-31"), Some("2011-04".into()));
+        assert_eq!(parse_date("2009-13-01"), Some("2009".into()));
+        assert_eq!(parse_date("2009-00"), Some("2009".into()));
+        assert_eq!(parse_date("09-03-07"), None);
+        assert_eq!(parse_date("1503"), Some("1503".into()));
+        assert_eq!(parse_date(""), None);
+    }
+
+    #[test]
+    fn parse_date_knows_leap_years() {
+        assert_eq!(parse_date("2008-02-29"), Some("2008-02-29".into()));
+        assert_eq!(parse_date("2000-02-29"), Some("2000-02-29".into()));
+        assert_eq!(parse_date("1900-02-29"), Some("1900-02".into()));
+        assert_eq!(parse_date("2009-02-29"), Some("2009-02".into()));
+    }
+
+    #[test]
+    fn most_precise_date_prevails() {
+        assert_eq!(most_precise_date(None, "2009"), Some("2009".into()));
+        assert_eq!(most_precise_date(Some("2009".into()), "2009-03-07"), Some("2009-03-07".into()));
+        assert_eq!(most_precise_date(Some("2009-03-07".into()), "2010"), Some("2009-03-07".into()));
+        // first tagged wins at equal precision
+        assert_eq!(most_precise_date(Some("2009".into()), "2010"), Some("2009".into()));
+        assert_eq!(most_precise_date(Some("2009".into()), "bogus"), Some("2009".into()));
+        assert_eq!(most_precise_date(None, "bogus"), None);
+    }
+
+
+    fn raw_tag(std_key: Option<StandardTagKey>, key: &str) -> Tag {
+        Tag::new(std_key, key, symphonia::core::meta::Value::from("value"))
+    }
+
+    #[test]
+    fn tag_fields_of_standard_and_raw_keys() {
+        let mapping = HashMap::new();
+        assert_eq!(tag_field(&raw_tag(Some(StandardTagKey::TrackTitle), "TIT2"), &mapping), Some("title"));
+        assert_eq!(tag_field(&raw_tag(None, "TXXX:MOOD"), &mapping), Some("mood"));
+        assert_eq!(tag_field(&raw_tag(Some(StandardTagKey::Lyrics), "USLT!eng"), &mapping), Some("lyrics"));
+        assert_eq!(tag_field(&raw_tag(None, "TXXX:UNKNOWN"), &mapping), None);
+    }
+
+    #[test]
+    fn partial_dates_are_not_dates() {
+        let mapping = HashMap::new();
+        for key in PARTIAL_DATE_KEYS {
+            assert_eq!(tag_field(&raw_tag(Some(StandardTagKey::Date), key), &mapping), None);
+        }
+        assert_eq!(tag_field(&raw_tag(Some(StandardTagKey::Date), "TDRC"), &mapping), Some("date"));
+    }
+
+    #[test]
+    fn tag_mapping_overrides() {
+        let mapping: HashMap<String, String> = vec![
+            ("txxx:catalognumber", "catalog_number"),
+            ("tpe1", "album_artist"),
+            ("uslt", "ignore"),
+            ("tdat", "date"),
+        ]
+        .into_iter()
+        .map(|(key, field)| (key.to_string(), field.to_string()))
+        .collect();
+        assert_eq!(
+            tag_field(&raw_tag(None, "TXXX:CATALOGNUMBER"), &mapping),
+            Some("catalog_number")
+        );
+        assert_eq!(tag_field(&raw_tag(Some(StandardTagKey::Artist), "TPE1"), &mapping), Some("album_artist"));
+        assert_eq!(tag_field(&raw_tag(Some(StandardTagKey::Lyrics), "USLT!eng"), &mapping), None);
+        assert_eq!(tag_field(&raw_tag(Some(StandardTagKey::Date), "TDAT"), &mapping), Some("date"));
+        // other keys keep their default field
+        assert_eq!(tag_field(&raw_tag(Some(StandardTagKey::Album), "TALB"), &mapping), Some("album"));
+    }
+
+
+    /// Sheet laying out one file per track, `Cargo.toml` being the second
+    const MULTI_FILE_SHEET: &str = "PERFORMER \"Miles Davis\"\n\
+        TITLE \"Kind of Blue\"\n\
+        FILE \"01.flac\" WAVE\nTRACK 01 AUDIO\nTITLE \"So What\"\nINDEX 01 00:00:00\n\
+        FILE \"Cargo.toml\" WAVE\nTRACK 02 AUDIO\nTITLE \"Freddie Freeloader\"\nINDEX 01 00:00:00\n";
+
+    #[test]
+    fn multi_file_sheet_leaves_tagged_tracks_whole() {
+        let mut track = probed_track();
+        track.title = "Freddie Freeloader (tagged)".into();
+        track.track_ref = "mbid-t".into();
+        track.track_number = Some(2);
+        track.track_total = Some(5);
+        track.cue_sheet = Some(CueSheet::parse(MULTI_FILE_SHEET));
+        track.apply_cue_sheet(Path::new("Cargo.toml"));
+        assert_eq!(track.title, "Freddie Freeloader (tagged)");
+        assert_eq!(track.album.as_deref(), Some("Kind of Blue"));
+
+        let recordings = track.recordings();
+        assert_eq!(recordings.len(), 1);
+        assert_eq!(recordings[0].track_ref, "mbid-t");
+        assert_eq!(recordings[0].span, None);
+        assert_eq!((recordings[0].track_number, recordings[0].track_total), (Some(2), Some(5)));
+    }
+
+    #[test]
+    fn multi_file_sheet_completes_untagged_tracks() {
+        let mut track = probed_track();
+        track.cue_sheet = Some(CueSheet::parse(MULTI_FILE_SHEET));
+        track.apply_cue_sheet(Path::new("Cargo.toml"));
+        assert_eq!(track.title, "Freddie Freeloader");
+        assert_eq!(track.artist, "Miles Davis");
+        assert_eq!(track.track_number, Some(2));
+        assert_eq!(track.recordings().len(), 1);
+    }
+}
@@ -1,3 +1,9 @@
+pub mod cue;
 pub mod library;
+pub mod lyrics;
 pub mod metadata;
+pub mod picture;
+pub mod quality;
+pub mod template;
 pub mod track;
+pub mod watch;
@@ -0,0 +1,250 @@
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use symphonia::core::meta::{ColorMode, StandardVisualKey, Visual};
+
+/// Number of images staged by this process, naming staging files uniquely
+static STAGED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// An image stored once in the image cache, whatever the number of tracks
+/// embedding it
+#[derive(Clone, Debug)]
+pub struct Picture {
+    /// SHA-256 digest (hex) of the image data
+    pub hash: String,
+    pub mime_type: String,
+    /// Width and height in pixels, when known
+    pub dimensions: Option<(u32, u32)>,
+    /// Image data size in bytes
+    pub size: u64,
+    /// Color mode (e.g. `Discrete` or `Indexed(256)`), as hinted by metadata
+    pub color_mode: Option<String>,
+    /// Bit depth of the unencoded image, as hinted by metadata
+    pub bits_per_pixel: Option<u32>,
+    /// What the picture shows (e.g. front cover or artist), when tagged
+    pub usage: Option<StandardVisualKey>,
+}
+
+impl Picture {
+    /// Caches a picture embedded in an audio file
+    pub fn from_visual(visual: &Visual, cache_dir: &Path) -> std::io::Result<Self> {
+        let mut picture = Self::cache(&visual.data, &visual.media_type, cache_dir)?;
+        picture.dimensions = picture
+            .dimensions
+            .or_else(|| visual.dimensions.map(|size| (size.width, size.height)));
+        picture.color_mode = visual.color_mode.map(|mode| match mode {
+            ColorMode::Discrete => "Discrete".into(),
+            ColorMode::Indexed(colors) => format!("Indexed({})", colors),
+        });
+        picture.bits_per_pixel = visual.bits_per_pixel.map(|bits| bits.get());
+        picture.usage = visual.usage;
+        Ok(picture)
+    }
+
+    /// Writes image data into the cache directory, unless already cached
+    pub fn cache(data: &[u8], mime_type: &str, cache_dir: &Path) -> std::io::Result<Self> {
+        let picture = Self {
+            hash: format!("{:x}", Sha256::digest(data)),
+            mime_type: mime_type.to_string(),
+            dimensions: image_dimensions(data),
+            size: data.len() as u64,
+            color_mode: None,
+            bits_per_pixel: None,
+            usage: None,
+        };
+        let path = picture.cache_path(cache_dir);
+        if !path.is_file() {
+            let dir = path.parent().unwrap_or(cache_dir);
+            std::fs::create_dir_all(dir)?;
+            // written aside then renamed, so concurrent probes never read a
+            // partially written image
+            let staged = dir.join(format!(
+                ".{}.{}-{}.tmp",
+                picture.hash,
+                std::process::id(),
+                STAGED_COUNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::write(&staged, data)?;
+            std::fs::rename(&staged, &path)?;
+        }
+        Ok(picture)
+    }
+
+    /// Preference of a picture as artwork (lowest first): front cover, then
+    /// pictures of unspecified usage, then any but icons
+    pub fn artwork_rank(&self) -> u8 {
+        match self.usage {
+            Some(StandardVisualKey::FrontCover) => 0,
+            None => 1,
+            Some(StandardVisualKey::FileIcon) | Some(StandardVisualKey::OtherIcon) => 3,
+            Some(_) => 2,
+        }
+    }
+
+    /// Cached image location: `<cache_dir>/<2 first hash digits>/<hash>`
+    pub fn cache_path(&self, cache_dir: &Path) -> PathBuf {
+        cache_dir.join(&self.hash[..2]).join(&self.hash)
+    }
+}
+
+/// Folder image lying in `dir` and matching `patterns` (by decreasing
+/// preference), along with its modification time
+pub fn find_sidecar(dir: &Path, patterns: &[String]) -> Option<(PathBuf, u64)> {
+    let mut candidates: Vec<(usize, PathBuf)> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter_map(|path| sidecar_rank(&path, patterns).map(|rank| (rank, path)))
+        .filter(|(_, path)| path.is_file())
+        .collect();
+    candidates.sort();
+    let (_, path) = candidates.into_iter().next()?;
+    let modified = path
+        .metadata()
+        .map(|metadata| super::metadata::modification_time(&metadata))
+        .unwrap_or(0);
+    Some((path, modified))
+}
+
+/// Index of the first pattern an image file name matches
+pub fn sidecar_rank(path: &Path, patterns: &[String]) -> Option<usize> {
+    let is_image = new_mime_guess::from_path(path)
+        .first()
+        .is_some_and(|mime| mime.type_() == "image");
+    if !is_image {
+        return None;
+    }
+    let name: Vec<char> = path.file_name()?.to_string_lossy().to_lowercase().chars().collect();
+    patterns.iter().position(|pattern| {
+        let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+        wildcard_match(&pattern, &name)
+    })
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any characters and
+/// `?` for a single one
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skipped| wildcard_match(rest, &name[skipped..])),
+        Some((&c, rest)) => match name.split_first() {
+            Some((&n, name_rest)) if c == '?' || c == n => wildcard_match(rest, name_rest),
+            _ => false,
+        },
+    }
+}
+
+/// Width and height of PNG, GIF and JPEG images, read from their header
+pub fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let width = u32::from_be_bytes(data.get(16..20)?.try_into().ok()?);
+        let height = u32::from_be_bytes(data.get(20..24)?.try_into().ok()?);
+        return Some((width, height));
+    }
+    if data.starts_with(b"GIF8") {
+        let width = u16::from_le_bytes(data.get(6..8)?.try_into().ok()?);
+        let height = u16::from_le_bytes(data.get(8..10)?.try_into().ok()?);
+        return Some((width.into(), height.into()));
+    }
+    if data.starts_with(&[0xff, 0xd8]) {
+        return jpeg_dimensions(data);
+    }
+    None
+}
+
+/// Dimensions held by the first start of frame segment of a JPEG image
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let mut offset = 2;
+    loop {
+        // segments start with (possibly many) 0xff fill bytes
+        if *data.get(offset)? != 0xff {
+            return None;
+        }
+        while *data.get(offset)? == 0xff {
+            offset += 1;
+        }
+        let marker = *data.get(offset)?;
+        offset += 1;
+        // standalone markers
+        if marker == 0x01 || (0xd0..=0xd7).contains(&marker) {
+            continue;
+        }
+        let length = u16::from_be_bytes(data.get(offset..offset + 2)?.try_into().ok()?) as usize;
+        // SOF0 to SOF15, except DHT, JPG and DAC
+        if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+            let height = u16::from_be_bytes(data.get(offset + 3..offset + 5)?.try_into().ok()?);
+            let width = u16::from_be_bytes(data.get(offset + 5..offset + 7)?.try_into().ok()?);
+            return Some((width.into(), height.into()));
+        }
+        offset += length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        wildcard_match(&pattern, &name)
+    }
+
+    #[test]
+    fn wildcard_patterns() {
+        assert!(matches("cover.*", "cover.jpg"));
+        assert!(matches("*front*", "album front.png"));
+        assert!(matches("*", ""));
+        assert!(matches("cd?.jpg", "cd1.jpg"));
+        assert!(!matches("cd?.jpg", "cd.jpg"));
+        assert!(!matches("cover.*", "back cover.jpg"));
+        assert!(!matches("folder.jpg", "folder.jpeg"));
+    }
+
+    #[test]
+    fn sidecar_ranks() {
+        let patterns = vec!["cover.*".to_string(), "folder.*".to_string(), "*".to_string()];
+        assert_eq!(sidecar_rank(Path::new("/music/Folder.JPG"), &patterns), Some(1));
+        assert_eq!(sidecar_rank(Path::new("/music/cover.png"), &patterns), Some(0));
+        assert_eq!(sidecar_rank(Path::new("/music/scan.gif"), &patterns), Some(2));
+        assert_eq!(sidecar_rank(Path::new("/music/cover.txt"), &patterns), None);
+    }
+
+    #[test]
+    fn png_dimensions() {
+        let mut data = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        data.extend(640u32.to_be_bytes());
+        data.extend(480u32.to_be_bytes());
+        assert_eq!(image_dimensions(&data), Some((640, 480)));
+        assert_eq!(image_dimensions(&data[..20]), None);
+    }
+
+    #[test]
+    fn gif_dimensions() {
+        let mut data = b"GIF89a".to_vec();
+        data.extend(320u16.to_le_bytes());
+        data.extend(200u16.to_le_bytes());
+        assert_eq!(image_dimensions(&data), Some((320, 200)));
+    }
+
+    #[test]
+    fn jpeg_dimensions_from_start_of_frame() {
+        // SOI, APP0 (4 bytes long), fill byte, then SOF2 (progressive)
+        let mut data = vec![0xff, 0xd8, 0xff, 0xe0, 0, 4, 0, 0, 0xff, 0xff, 0xc2, 0, 17, 8];
+        data.extend(300u16.to_be_bytes());
+        data.extend(500u16.to_be_bytes());
+        assert_eq!(image_dimensions(&data), Some((500, 300)));
+    }
+
+    #[test]
+    fn jpeg_huffman_table_is_not_a_frame() {
+        let data = [0xff, 0xd8, 0xff, 0xc4, 0, 7, 0, 1, 0, 1, 0, 0xff, 0xd9];
+        assert_eq!(image_dimensions(&data), None);
+    }
+
+    #[test]
+    fn unknown_image_format() {
+        assert_eq!(image_dimensions(b"RIFF\0\0\0\0WEBP"), None);
+    }
+}
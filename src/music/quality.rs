@@ -0,0 +1,163 @@
+use std::str::FromStr;
+
+use super::metadata::UNINITIALIZED_STR;
+use super::track::Track;
+use crate::CanariaError;
+
+/// Track fields a quality policy may require or recommend
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QualityField {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    TrackNumber,
+    Year,
+    Genre,
+    Artwork,
+    Lyrics,
+    TrackMbid,
+    ArtistMbid,
+    AlbumMbid,
+}
+
+impl FromStr for QualityField {
+    type Err = CanariaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "title" => Ok(Self::Title),
+            "artist" => Ok(Self::Artist),
+            "album" => Ok(Self::Album),
+            "album_artist" => Ok(Self::AlbumArtist),
+            "track_number" => Ok(Self::TrackNumber),
+            "year" => Ok(Self::Year),
+            "genre" => Ok(Self::Genre),
+            "artwork" => Ok(Self::Artwork),
+            "lyrics" => Ok(Self::Lyrics),
+            "track_mbid" => Ok(Self::TrackMbid),
+            "artist_mbid" => Ok(Self::ArtistMbid),
+            "album_mbid" => Ok(Self::AlbumMbid),
+            _ => Err(format!("unknown quality field `{}`", s).into()),
+        }
+    }
+}
+
+impl QualityField {
+    /// Issue reported when the field is missing (e.g. `missingArtwork`)
+    pub fn issue(&self) -> &'static str {
+        match self {
+            Self::Title => "missingTitle",
+            Self::Artist => "missingArtist",
+            Self::Album => "missingAlbum",
+            Self::AlbumArtist => "missingAlbumArtist",
+            Self::TrackNumber => "missingTrackNumber",
+            Self::Year => "missingYear",
+            Self::Genre => "missingGenre",
+            Self::Artwork => "missingArtwork",
+            Self::Lyrics => "missingLyrics",
+            Self::TrackMbid => "missingTrackMbid",
+            Self::ArtistMbid => "missingArtistMbid",
+            Self::AlbumMbid => "missingAlbumMbid",
+        }
+    }
+
+    /// Whether a track lacks the field. Inferred fields are not missing
+    pub fn is_missing(&self, track: &Track) -> bool {
+        let blank = |value: &str| value.is_empty() || value == UNINITIALIZED_STR;
+        match self {
+            Self::Title => blank(&track.title),
+            Self::Artist => blank(&track.artist),
+            Self::Album => track.album.as_deref().is_none_or(blank),
+            Self::AlbumArtist => track.album_artist.as_deref().is_none_or(blank),
+            Self::TrackNumber => track.track_number.is_none(),
+            Self::Year => track.original_year.is_none(),
+            Self::Genre => track.genres.is_empty(),
+            Self::Artwork => track.artwork().is_none(),
+            Self::Lyrics => track.lyrics.is_none() && track.synced_lyrics.is_none(),
+            Self::TrackMbid => blank(&track.track_ref),
+            Self::ArtistMbid => track.artist_ref.is_empty(),
+            Self::AlbumMbid => track.album_ref.as_deref().is_none_or(blank),
+        }
+    }
+}
+
+/// Which files are good enough to be catalogued, and what is worth curating
+#[derive(Clone, Debug)]
+pub struct QualityPolicy {
+    /// Fields without which a file is rejected
+    pub required: Vec<QualityField>,
+
+    /// Fields whose absence is only reported
+    pub recommended: Vec<QualityField>,
+
+    /// Shortest playback duration (in seconds) accepted
+    pub min_duration_seconds: u32,
+
+    /// Lowest bitrate (in kbps) accepted
+    pub min_bitrate: u32,
+
+    /// Record rejected files as audio objects flagged as needing tagging,
+    /// rather than ignoring them
+    pub record_rejected: bool,
+}
+
+impl Default for QualityPolicy {
+    fn default() -> Self {
+        Self {
+            required: vec![QualityField::Title, QualityField::Artist],
+            recommended: vec![
+                QualityField::TrackMbid,
+                QualityField::ArtistMbid,
+                QualityField::AlbumMbid,
+                QualityField::Artwork,
+            ],
+            min_duration_seconds: 0,
+            min_bitrate: 0,
+            record_rejected: false,
+        }
+    }
+}
+
+/// Outcome of a track quality check
+#[derive(Debug, Default)]
+pub struct QualityReport {
+    /// Every issue found, required fields and thresholds included
+    pub issues: Vec<String>,
+
+    /// Issues making the file rejected
+    pub rejections: Vec<String>,
+}
+
+impl QualityPolicy {
+    /// Issues of a track, as of this policy
+    pub fn check(&self, track: &Track) -> QualityReport {
+        let mut report = QualityReport::default();
+        for field in &self.required {
+            if field.is_missing(track) {
+                report.reject(field.issue());
+            }
+        }
+        for field in &self.recommended {
+            if field.is_missing(track) && !report.issues.iter().any(|issue| issue == field.issue()) {
+                report.issues.push(field.issue().into());
+            }
+        }
+        // durations and bitrates are unknown for some streams, which is not
+        // held against them
+        if track.duration_seconds.is_some_and(|seconds| seconds < self.min_duration_seconds) {
+            report.reject("shortDuration");
+        }
+        if track.bitrate.is_some_and(|bitrate| bitrate < self.min_bitrate) {
+            report.reject("lowBitrate");
+        }
+        report
+    }
+}
+
+impl QualityReport {
+    fn reject(&mut self, issue: &str) {
+        self.issues.push(issue.into());
+        self.rejections.push(issue.into());
+    }
+}
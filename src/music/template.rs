@@ -0,0 +1,172 @@
+use regex::Regex;
+use std::path::Path;
+
+use crate::CanariaError;
+
+/// Fields a path template may capture
+const TEMPLATE_FIELDS: [&str; 8] = [
+    "albumartist",
+    "artist",
+    "album",
+    "title",
+    "track",
+    "disc",
+    "year",
+    "genre",
+];
+
+/// Common file name layouts, tried after configured templates
+const FILENAME_PATTERNS: [&str; 6] = [
+    "{track} - {artist} - {title}",
+    "{track}. {artist} - {title}",
+    "{track} - {title}",
+    "{track}. {title}",
+    "{artist} - {title}",
+    "{track} {title}",
+];
+
+/// Layout of file paths, such as `{albumartist}/{year} - {album}/{track:02} {title}`,
+/// matched against the trailing components of a path (file extension excluded)
+#[derive(Clone, Debug)]
+pub struct PathTemplate {
+    pattern: String,
+    regex: Regex,
+    /// Number of path components matched
+    depth: usize,
+}
+
+impl PathTemplate {
+    pub fn new(pattern: &str) -> Result<Self, CanariaError> {
+        let mut regex = String::from("^");
+        let mut rest = pattern.trim_matches('/');
+        while let Some(start) = rest.find('{') {
+            regex.push_str(&regex::escape(&rest[..start]));
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed placeholder in path template `{}`", pattern))?;
+            // a format spec (e.g. `{track:02}`) only matters to humans
+            let field = rest[start + 1..start + end].split(':').next().unwrap_or_default();
+            regex.push_str(&match field {
+                "track" | "disc" => format!("(?P<{}>\\d{{1,3}})", field),
+                "year" => format!("(?P<{}>\\d{{4}})", field),
+                _ if TEMPLATE_FIELDS.contains(&field) => format!("(?P<{}>[^/]+?)", field),
+                _ => return Err(format!("unknown field `{}` in path template `{}`", field, pattern).into()),
+            });
+            rest = &rest[start + end + 1..];
+        }
+        regex.push_str(&regex::escape(rest));
+        regex.push('$');
+        Ok(Self {
+            pattern: pattern.to_string(),
+            regex: Regex::new(&regex)?,
+            depth: pattern.trim_matches('/').split('/').count(),
+        })
+    }
+
+    /// Built-in file name patterns (e.g. `Artist - Title`)
+    pub fn filename_patterns() -> Vec<Self> {
+        FILENAME_PATTERNS
+            .iter()
+            .map(|pattern| Self::new(pattern).expect("bogus file name pattern"))
+            .collect()
+    }
+
+    /// Field values captured from a file path, when matching
+    pub fn capture(&self, file: &Path) -> Option<Vec<(&'static str, String)>> {
+        let mut components: Vec<String> = file
+            .with_extension("")
+            .components()
+            .rev()
+            .take(self.depth)
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        if components.len() < self.depth {
+            return None;
+        }
+        components.reverse();
+        let path = components.join("/");
+        let captures = self.regex.captures(&path)?;
+        Some(
+            TEMPLATE_FIELDS
+                .iter()
+                .filter_map(|field| {
+                    let value = captures.name(field)?.as_str().trim();
+                    Some((*field, value.to_string())).filter(|_| !value.is_empty())
+                })
+                .collect(),
+        )
+    }
+}
+
+impl std::fmt::Display for PathTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captured(template: &PathTemplate, file: &str) -> Option<Vec<(&'static str, String)>> {
+        template.capture(Path::new(file))
+    }
+
+    fn fields(values: &[(&'static str, &str)]) -> Vec<(&'static str, String)> {
+        values.iter().map(|(field, value)| (*field, value.to_string())).collect()
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        assert!(PathTemplate::new("{artist}/{album").is_err());
+        assert!(PathTemplate::new("{artist}/{composer} - {title}").is_err());
+        assert!(PathTemplate::new("{artist}/{album}/{artist} - {title}").is_err());
+    }
+
+    #[test]
+    fn album_layout() {
+        let template = PathTemplate::new("/{albumartist}/{year} - {album}/{track:02} {title}/").unwrap();
+        assert_eq!(template.to_string(), "/{albumartist}/{year} - {album}/{track:02} {title}/");
+        assert_eq!(
+            captured(&template, "/music/Miles Davis/1959 - Kind of Blue/01 So What.flac"),
+            Some(fields(&[
+                ("albumartist", "Miles Davis"),
+                ("album", "Kind of Blue"),
+                ("title", "So What"),
+                ("track", "01"),
+                ("year", "1959"),
+            ]))
+        );
+        // a year is made of four digits
+        assert_eq!(captured(&template, "/music/Miles Davis/59 - Kind of Blue/01 So What.flac"), None);
+    }
+
+    #[test]
+    fn paths_shallower_than_template_do_not_match() {
+        let template = PathTemplate::new("{artist}/{album}/{title}").unwrap();
+        assert_eq!(captured(&template, "Kind of Blue/So What.flac"), None);
+        assert_eq!(
+            captured(&template, "Kind of Blue/So What/Take 2.flac"),
+            Some(fields(&[("artist", "Kind of Blue"), ("album", "So What"), ("title", "Take 2")]))
+        );
+    }
+
+    #[test]
+    fn filename_patterns_by_preference() {
+        let patterns = PathTemplate::filename_patterns();
+        let first_match = |file: &str| patterns.iter().find_map(|pattern| captured(pattern, file));
+        assert_eq!(
+            first_match("/music/03 - Miles Davis - Blue in Green.mp3"),
+            Some(fields(&[("artist", "Miles Davis"), ("title", "Blue in Green"), ("track", "03")]))
+        );
+        assert_eq!(
+            first_match("/music/03. Blue in Green.mp3"),
+            Some(fields(&[("title", "Blue in Green"), ("track", "03")]))
+        );
+        assert_eq!(
+            first_match("/music/Miles Davis - Blue in Green.mp3"),
+            Some(fields(&[("artist", "Miles Davis"), ("title", "Blue in Green")]))
+        );
+        assert_eq!(first_match("/music/Blue in Green.mp3"), None);
+    }
+}
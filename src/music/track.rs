@@ -2,7 +2,12 @@ use std::path::{Path, PathBuf};
 use serde::Deserialize;
 
 use crate::CanariaError;
+use super::cue::CueSheet;
 use super::metadata;
+use super::picture::Picture;
+
+/// Album artist of compilations not tagged with their own
+const VARIOUS_ARTISTS: &str = "Various Artists";
 
 #[derive(Clone,Debug,Deserialize)]
 pub struct Track {
@@ -12,12 +17,28 @@ pub struct Track {
     /// Music artist as should be displayed in the music player
     pub artist: String,
     
+    /// Individual artist names, in the same order as `artist_ref`
+    #[serde(skip)]
+    pub artists: Vec<String>,
+
+    /// Individual artist sort names, in the same order as `artist_ref`
+    #[serde(skip)]
+    pub artists_sort: Vec<String>,
+
     /// A list of artist's ID`s referenced
     #[serde(skip)]
     pub artist_ref: Vec<String>,
     
     /// Tracks original release year
     pub original_year: Option<u16>,
+
+    /// Release date, as precise as tagged (`YYYY`, `YYYY-MM` or `YYYY-MM-DD`)
+    #[serde(skip)]
+    pub release_date: Option<String>,
+
+    /// Original release date, as precise as tagged
+    #[serde(skip)]
+    pub original_date: Option<String>,
     
     /// The album (if any) that track belongs to
     #[serde(skip)]
@@ -26,33 +47,382 @@ pub struct Track {
     /// The ID of the album mentioned by "album" field
     #[serde(skip)]
     pub album_ref: Option<String>,
+
+    /// Label catalog number of the album release
+    #[serde(skip)]
+    pub catalog_number: Option<String>,
+
+    /// Artist credited for the whole album, when tagged
+    #[serde(skip)]
+    pub album_artist: Option<String>,
+
+    /// Album artist sort name
+    #[serde(skip)]
+    pub album_artist_sort: Option<String>,
+
+    /// The IDs of the artists mentioned by "album_artist" field
+    #[serde(skip)]
+    pub album_artist_ref: Vec<String>,
+
+    /// Whether the album gathers tracks of various artists
+    #[serde(skip)]
+    pub compilation: bool,
     
-    /// The list of tags associated with that track
+    /// Position of the track on its disc
+    pub track_number: Option<u32>,
+    /// Number of tracks on the disc
+    pub track_total: Option<u32>,
+    /// Position of the disc within its album
+    pub disc_number: Option<u32>,
+    /// Number of discs of the album
+    pub disc_total: Option<u32>,
+
+    /// Genres of the track
+    #[serde(skip)]
+    pub genres: Vec<String>,
+
+    /// The list of tags (e.g. folksonomy tags or moods) associated with that track
     #[serde(skip)]
     pub tags: Vec<String>,
     
+    /// Unsynchronized lyrics
+    #[serde(skip)]
+    pub lyrics: Option<String>,
+
+    /// Synchronized lyrics, LRC formatted
+    #[serde(skip)]
+    pub synced_lyrics: Option<String>,
+
     /// Recording ID, the real specification of a track
     pub track_ref: String,
     
     /// Playback duration in seconds
     pub duration_seconds: Option<u32>,
+
+    /// Fields (as named in path templates) inferred from the file path
+    /// rather than read from tags
+    #[serde(skip)]
+    pub inferred: Vec<String>,
+
+    /// Quality issues found by the metadata quality policy (e.g.
+    /// `missingArtwork`)
+    #[serde(skip)]
+    pub quality_issues: Vec<String>,
+
+    /// Rejected by the metadata quality policy yet recorded, as an audio
+    /// file only
+    #[serde(skip)]
+    pub needs_tagging: bool,
+
+    /// CUE sheet laying out many tracks in the file, embedded or sidecar
+    #[serde(skip)]
+    pub cue_sheet: Option<CueSheet>,
+
+    /// Time range (milliseconds) of a recording laid out by a CUE sheet
+    /// within its audio file
+    #[serde(skip)]
+    pub span: Option<(u32, u32)>,
+
+    /// SHA-256 digest (hex) of the audio payload, tags excluded
+    #[serde(skip)]
+    pub content_hash: Option<String>,
     
     /// File size in bytes
     pub file_path: PathBuf,
     pub file_size: u64,
+    /// File modification time (seconds since UNIX epoch)
+    #[serde(default)]
+    pub file_modified: u64,
     pub mime_type: String,
     pub extension: String,
+
+    /// Audio codec short name (e.g. `flac`, `mp3`)
+    pub codec: Option<String>,
+    /// Sampling frequency in Hz
+    pub sample_rate: Option<u32>,
+    /// Bit depth of decoded samples
+    pub bits_per_sample: Option<u32>,
+    /// Number of audio channels
+    pub channels: Option<u32>,
+    /// Audio channels arrangement (e.g. `Stereo`)
+    pub channel_layout: Option<String>,
+    /// Average bitrate in kilobits per second
+    pub bitrate: Option<u32>,
     
-    /// Track picture
-    pub picture_mime_type: Option<String>,
-    /// base64 encoded picture data
-    pub picture: Option<String>,
+    /// Track pictures, stored in the image cache
+    #[serde(skip)]
+    pub pictures: Vec<Picture>,
+    /// Folder image standing for the front cover, when none is embedded
+    #[serde(skip)]
+    pub cover: Option<Picture>,
+    /// Folder image modification time (seconds since UNIX epoch, 0 if none)
+    #[serde(default)]
+    pub cover_modified: u64,
+    /// Latest modification time of the CUE sheets lying next to the file
+    /// (seconds since UNIX epoch, 0 if none)
+    #[serde(default)]
+    pub cue_modified: u64,
+    /// Lyrics sidecar modification time (seconds since UNIX epoch, 0 if none)
+    #[serde(default)]
+    pub lyrics_modified: u64,
+}
+
+/// An artist along with its names, as credited by a track
+#[derive(Clone, Debug)]
+pub struct ArtistCredit {
+    /// MusicBrainz artist ID, if catalogued
+    pub reference: Option<String>,
+    pub name: Option<String>,
+    pub sort_name: Option<String>,
 }
 
 impl Track {
-    pub fn from_file(path: &Path) -> Result<Self, CanariaError> {
-        let t_from_file = metadata::extract_metadata(path)?;
+    pub fn from_file(path: &Path, options: &metadata::MetadataOptions) -> Result<Self, CanariaError> {
+        let t_from_file = metadata::extract_metadata(path, options)?;
         log::debug!("extracted {:#?}", t_from_file);
         Ok(t_from_file)
     }
+
+    /// Artist credited for the whole album: the tagged album artist or, if
+    /// missing, various artists for compilations and the track artist otherwise
+    pub fn album_artist(&self) -> &str {
+        match &self.album_artist {
+            Some(album_artist) => album_artist,
+            None if self.compilation => VARIOUS_ARTISTS,
+            None => &self.artist,
+        }
+    }
+
+    /// Recordings held by the file: one per CUE sheet track when the sheet
+    /// lays out many in the file, sharing the file tags and audio properties,
+    /// or the track itself
+    pub fn recordings(&self) -> Vec<Track> {
+        let cue_tracks = match &self.cue_sheet {
+            Some(sheet) => sheet.tracks_of(&self.file_path),
+            None => Vec::new(),
+        };
+        if cue_tracks.len() < 2 || self.needs_tagging {
+            return vec![self.clone()];
+        }
+        let file_end_ms = self.duration_seconds.unwrap_or(0) * 1000;
+        let track_total = cue_tracks.len() as u32;
+        cue_tracks
+            .into_iter()
+            .map(|cue_track| {
+                let mut recording = self.clone();
+                recording.cue_sheet = None;
+                let end_ms = cue_track.end_ms.unwrap_or(file_end_ms).max(cue_track.start_ms);
+                recording.span = Some((cue_track.start_ms, end_ms));
+                recording.duration_seconds =
+                    Some(((end_ms - cue_track.start_ms) as f64 / 1000.0).round() as u32);
+                let number = cue_track.number;
+                recording.title = cue_track.title.unwrap_or_else(|| format!("Track {:02}", number));
+                if let Some(performer) = cue_track.performer.filter(|performer| *performer != self.artist) {
+                    recording.artist = performer.clone();
+                    recording.artists = vec![performer];
+                    recording.artists_sort.clear();
+                    recording.artist_ref.clear();
+                }
+                // not a catalogued recording, however the file is tagged
+                let base_ref = self
+                    .track_ref
+                    .strip_prefix(metadata::LOCAL_REF_PREFIX)
+                    .unwrap_or(&self.track_ref);
+                recording.track_ref = format!("{}{}#{}", metadata::LOCAL_REF_PREFIX, base_ref, number);
+                recording.track_number = Some(number);
+                recording.track_total = Some(track_total);
+                // file wide lyrics can't be told apart between tracks
+                recording.lyrics = None;
+                recording.synced_lyrics = None;
+                recording
+            })
+            .collect()
+    }
+
+    /// Primary artwork: the embedded front cover, else the folder image,
+    /// else the most suitable embedded picture
+    pub fn artwork(&self) -> Option<&Picture> {
+        let embedded = self.pictures.iter().min_by_key(|picture| picture.artwork_rank());
+        match embedded {
+            Some(picture) if picture.artwork_rank() == 0 => Some(picture),
+            _ => self.cover.as_ref().or(embedded),
+        }
+    }
+
+    /// Whether a front cover is embedded
+    pub fn has_front_cover(&self) -> bool {
+        self.pictures.iter().any(|picture| picture.artwork_rank() == 0)
+    }
+
+    /// Identity of an album not catalogued: its normalized artist and title
+    /// along with the directory holding the track
+    pub fn album_key(&self) -> Option<String> {
+        let title = self.album.as_deref().filter(|title| !title.trim().is_empty())?;
+        let directory = self.file_path.parent().unwrap_or(&self.file_path);
+        Some(format!(
+            "{}|{}|{}",
+            metadata::name_key(self.album_artist()),
+            metadata::name_key(title),
+            directory.to_string_lossy()
+        ))
+    }
+
+    /// Credited artists paired with their names. Referenced artists names
+    /// can only be paired when tagged as many as references, while tracks
+    /// without any reference credit artists by name only
+    pub fn artist_credits(&self) -> Vec<ArtistCredit> {
+        let names = if self.artist_ref.is_empty() && self.artists.is_empty() && !self.artist.is_empty() {
+            vec![self.artist.clone()]
+        } else {
+            self.artists.clone()
+        };
+        credits(&self.artist_ref, &names, &self.artists_sort, &self.artist)
+    }
+
+    /// Credited album artists, falling back to the track artists when the
+    /// album is neither tagged with its own artist nor a compilation
+    pub fn album_artist_credits(&self) -> Vec<ArtistCredit> {
+        if self.album_artist.is_none() && !self.compilation {
+            return self.artist_credits();
+        }
+        let sort_names: Vec<String> = self.album_artist_sort.iter().cloned().collect();
+        credits(
+            &self.album_artist_ref,
+            &[self.album_artist().to_string()],
+            &sort_names,
+            self.album_artist(),
+        )
+    }
+}
+
+/// Pairs artist references with names and sort names, as long as they are
+/// tagged as many. A single reference left unpaired is credited with the
+/// `credited` artist string (e.g. `A feat. B`), else the joined names
+fn credits(references: &[String], names: &[String], sort_names: &[String], credited: &str) -> Vec<ArtistCredit> {
+    let sort_names_paired = |count: usize| sort_names.len() == count;
+    if references.is_empty() {
+        let count = names.len();
+        return names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| ArtistCredit {
+                reference: None,
+                name: Some(name.clone()),
+                sort_name: sort_names.get(index).filter(|_| sort_names_paired(count)).cloned(),
+            })
+            .collect();
+    }
+
+    let count = references.len();
+    let names_paired = names.len() == count;
+    if !names_paired {
+        log::warn!(
+            "{} artist names for {} artist references: {:?}",
+            names.len(),
+            count,
+            names
+        );
+    }
+    let fallback_name = Some(credited.trim())
+        .filter(|credited| !credited.is_empty() && *credited != metadata::UNINITIALIZED_STR)
+        .map(String::from)
+        .or_else(|| Some(names.join("; ")).filter(|joined| !joined.is_empty()))
+        .filter(|_| count == 1);
+    references
+        .iter()
+        .enumerate()
+        .map(|(index, reference)| ArtistCredit {
+            reference: Some(reference.clone()),
+            name: if names_paired {
+                names.get(index).cloned()
+            } else {
+                fallback_name.clone()
+            },
+            sort_name: sort_names.get(index).filter(|_| sort_names_paired(count)).cloned(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Track of a tagged file, built without reading any file
+    pub(crate) fn tagged_track(track_ref: &str) -> Track {
+        serde_json::from_value(serde_json::json!({
+            "title": "Title",
+            "artist": "Artist",
+            "track_ref": track_ref,
+            "duration_seconds": 60,
+            "file_path": "/music/a.flac",
+            "file_size": 1,
+            "mime_type": "audio/flac",
+            "extension": "flac",
+            "codec": null,
+            "sample_rate": null,
+            "bits_per_sample": null,
+            "channels": null,
+            "channel_layout": null,
+            "bitrate": null,
+            "original_year": null,
+            "track_number": null,
+            "track_total": null,
+            "disc_number": null,
+            "disc_total": null,
+        }))
+        .unwrap()
+    }
+
+    pub(crate) fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn credits_pair_names_with_references() {
+        let artists = credits(&strings(&["id-a", "id-b"]), &strings(&["A", "B"]), &[], "A & B");
+        assert_eq!(artists[0].name.as_deref(), Some("A"));
+        assert_eq!(artists[1].name.as_deref(), Some("B"));
+    }
+
+    #[test]
+    fn credits_name_single_unpaired_reference_after_credited_artist() {
+        let artists = credits(&strings(&["id-a"]), &[], &[], "A feat. B");
+        assert_eq!(artists[0].name.as_deref(), Some("A feat. B"));
+
+        let artists = credits(&strings(&["id-a"]), &strings(&["A", "B"]), &[], "");
+        assert_eq!(artists[0].name.as_deref(), Some("A; B"));
+    }
+
+    #[test]
+    fn credits_leave_many_unpaired_references_nameless() {
+        let artists = credits(&strings(&["id-a", "id-b"]), &strings(&["A"]), &[], "A");
+        assert!(artists.iter().all(|credit| credit.name.is_none()));
+    }
+
+    #[test]
+    fn credits_by_name_without_references() {
+        let artists = credits(&[], &strings(&["A", "B"]), &strings(&["A, The"]), "A & B");
+        assert_eq!(artists.len(), 2);
+        assert!(artists.iter().all(|credit| credit.reference.is_none()));
+        // sort names are only paired when tagged as many
+        assert!(artists.iter().all(|credit| credit.sort_name.is_none()));
+    }
+
+
+    #[test]
+    fn cue_split_recordings_get_local_references() {
+        let mut track = tagged_track("LOCAL:abcd");
+        track.duration_seconds = Some(600);
+        track.cue_sheet = Some(CueSheet::parse(
+            "FILE \"a.wav\" WAVE\n\
+             TRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+             TRACK 02 AUDIO\nINDEX 01 05:00:00\n",
+        ));
+        let refs: Vec<String> = track.recordings().into_iter().map(|recording| recording.track_ref).collect();
+        assert_eq!(refs, strings(&["LOCAL:abcd#1", "LOCAL:abcd#2"]));
+
+        track.track_ref = "mbid-whole".into();
+        let refs: Vec<String> = track.recordings().into_iter().map(|recording| recording.track_ref).collect();
+        assert_eq!(refs, strings(&["LOCAL:mbid-whole#1", "LOCAL:mbid-whole#2"]));
+    }
 }
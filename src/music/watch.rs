@@ -0,0 +1,126 @@
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::library::{IngestOptions, Library};
+use super::picture;
+use crate::CanariaError;
+use crate::DgraphClient;
+
+/// Library path watcher, buffering filesystem events until they get
+/// ingested, so changes made during the initial ingestion are not missed
+pub struct LibraryWatcher {
+    path: PathBuf,
+    rx: mpsc::UnboundedReceiver<Event>,
+    _watcher: RecommendedWatcher,
+}
+
+impl LibraryWatcher {
+    /// Starts watching a library path
+    pub fn new(path: &Path) -> Result<Self, CanariaError> {
+        // event paths are compared with canonical file paths
+        let path = path.canonicalize()?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+            Ok(event) => {
+                let _ = tx.send(event);
+            }
+            Err(err) => log::error!("watch: {}", err),
+        })?;
+        watcher.watch(&path, RecursiveMode::Recursive)?;
+        Ok(Self {
+            path,
+            rx,
+            _watcher: watcher,
+        })
+    }
+
+    /// Continuously ingests library path changes.
+    ///
+    /// Filesystem events are gathered until `debounce` passes without any new
+    /// event (e.g. a whole album being copied), then created or modified paths
+    /// get ingested and removed ones reconciled. Renames are handled as a
+    /// removal plus a creation, which reconciliation recognizes as a move.
+    pub async fn watch(
+        mut self,
+        mut lib: Library,
+        db: &DgraphClient,
+        options: &IngestOptions,
+        debounce: Duration,
+    ) -> Result<(), CanariaError> {
+        log::info!("watching {}", self.path.display());
+        while let Some(event) = self.rx.recv().await {
+            let mut changed: HashSet<PathBuf> = HashSet::new();
+            let mut removed: HashSet<PathBuf> = HashSet::new();
+            let mut next = Some(event);
+            while let Some(event) = next {
+                collect_event(event, &mut changed, &mut removed);
+                next = match tokio::time::timeout(debounce, self.rx.recv()).await {
+                    Ok(Some(event)) => Some(event),
+                    Ok(None) => return Ok(()),
+                    Err(_) => None,
+                };
+            }
+
+            // sidecar changes affect the audio files next to them, while other
+            // files not ingested are of no interest
+            let mut removed_files: Vec<PathBuf> = Vec::new();
+            for path in removed {
+                match sidecar_dir(&path, options) {
+                    Some(dir) => {
+                        changed.insert(dir);
+                    }
+                    None if !options.is_ignored(&path) => removed_files.push(path),
+                    None => (),
+                }
+            }
+            let changed: HashSet<PathBuf> = changed
+                .into_iter()
+                .map(|path| sidecar_dir(&path, options).unwrap_or(path))
+                .collect();
+            let changed: Vec<PathBuf> = changed.into_iter().filter(|path| path.exists()).collect();
+            if changed.is_empty() && removed_files.is_empty() {
+                continue;
+            }
+            log::info!("ingesting {} changed paths", changed.len());
+            match lib.ingest_changes(db, changed, removed_files, options).await {
+                Ok(report) if report.failed > 0 => log::error!("watch ingestion: {}", report),
+                Ok(report) => log::info!("watch ingestion: {}", report),
+                Err(err) => log::error!("watch ingestion: {}", err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Directory of a sidecar file (folder image, lyrics or CUE sheet), whose
+/// change affects the audio files lying next to it
+fn sidecar_dir(path: &Path, options: &IngestOptions) -> Option<PathBuf> {
+    let is_text = path.extension().is_some_and(|extension| {
+        extension.eq_ignore_ascii_case("lrc") || extension.eq_ignore_ascii_case("cue")
+    });
+    if is_text || picture::sidecar_rank(path, &options.metadata.cover_patterns).is_some() {
+        return path.parent().map(PathBuf::from);
+    }
+    None
+}
+
+/// Gathers paths to be ingested and paths removed from a filesystem event
+fn collect_event(event: Event, changed: &mut HashSet<PathBuf>, removed: &mut HashSet<PathBuf>) {
+    log::debug!("watch event: {:?}", event);
+    match event.kind {
+        EventKind::Create(_) => changed.extend(event.paths),
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => removed.extend(event.paths),
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            let mut paths = event.paths.into_iter();
+            removed.extend(paths.next());
+            changed.extend(paths);
+        }
+        EventKind::Modify(_) => changed.extend(event.paths),
+        EventKind::Remove(_) => removed.extend(event.paths),
+        EventKind::Access(_) | EventKind::Any | EventKind::Other => (),
+    }
+}